@@ -0,0 +1,65 @@
+//! Measures how long an `Allocate` command takes through the message bus and
+//! SQLite as the number of unrelated SKUs in the database grows. With the
+//! product loaded by SKU the latency should stay flat.
+//!
+//! Run with `cargo bench -p webapp --bench allocate`.
+use std::time::{Duration, Instant};
+
+use domain::commands::Command;
+use infrastructure::unit_of_work::SqlxUnitOfWorkFactory;
+use service_layer::MessageBus;
+use sqlx::sqlite::SqlitePool;
+
+const ALLOCATIONS: u32 = 200;
+
+#[tokio::main]
+async fn main() {
+    for unrelated_skus in [0, 1_000, 10_000] {
+        let latency = measure(unrelated_skus).await;
+        println!(
+            "allocate with {:>6} unrelated skus: {:>8.1?} per command",
+            unrelated_skus, latency
+        );
+    }
+}
+
+async fn measure(unrelated_skus: u32) -> Duration {
+    let db_pool = SqlitePool::connect("sqlite::memory:")
+        .await
+        .expect("Failed to connect to db");
+    infrastructure::run_migrations(&db_pool)
+        .await
+        .expect("failed to run migrations");
+    sqlx::query(
+        "WITH RECURSIVE n(i) AS (SELECT 1 UNION ALL SELECT i + 1 FROM n WHERE i < $1)
+        INSERT INTO batches (reference, sku, _purchased_quantity, eta)
+        SELECT 'unrelated-' || i, 'UNRELATED-' || i, 100, null FROM n",
+    )
+    .bind(unrelated_skus)
+    .execute(&db_pool)
+    .await
+    .expect("insert unrelated batches");
+
+    let bus = MessageBus::new(SqlxUnitOfWorkFactory::new(db_pool));
+    bus.handle(Command::CreateBatch {
+        reference: "batch1".to_owned(),
+        sku: "BENCH-LAMP".to_owned(),
+        qty: ALLOCATIONS,
+        eta: None,
+        expiry: None,
+    })
+    .await
+    .expect("create batch");
+
+    let start = Instant::now();
+    for i in 0..ALLOCATIONS {
+        bus.handle(Command::Allocate {
+            orderid: format!("order{}", i),
+            sku: "BENCH-LAMP".to_owned(),
+            qty: 1,
+        })
+        .await
+        .expect("allocate");
+    }
+    start.elapsed() / ALLOCATIONS
+}
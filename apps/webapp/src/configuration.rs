@@ -0,0 +1,354 @@
+//! Settings of the webapp binary: built-in defaults, overridden by a JSON
+//! config file, overridden by `WEBAPP_*` environment variables.
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use domain::allocation::{self, AllocationStrategies};
+
+/// Config file read when `WEBAPP_CONFIG` is not set; it may be absent.
+pub const DEFAULT_CONFIG_FILE: &str = "webapp.json";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("cannot read config file '{path}': {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("invalid config file '{path}': {source}")]
+    Parse {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    #[error("invalid value '{value}' for {key}: {reason}")]
+    Invalid {
+        key: String,
+        value: String,
+        reason: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(Self::Error),
+            "warn" => Ok(Self::Warn),
+            "info" => Ok(Self::Info),
+            "debug" => Ok(Self::Debug),
+            _ => Err("expected one of error, warn, info, debug".to_owned()),
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let level = match self {
+            Self::Error => "error",
+            Self::Warn => "warn",
+            Self::Info => "info",
+            Self::Debug => "debug",
+        };
+        f.write_str(level)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub host: String,
+    pub port: u16,
+    pub database_url: String,
+    pub pool_size: u32,
+    pub log_level: LogLevel,
+    /// UTC offset of the warehouse, like `+02:00`; batch dates are dates
+    /// there.
+    pub warehouse_utc_offset: String,
+    pub allocation: AllocationSettings,
+}
+
+/// Which allocation strategy each SKU is allocated with, by the names of
+/// [`allocation::builtin`].
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AllocationSettings {
+    /// Strategy of the SKUs not listed in `strategies`.
+    pub default_strategy: String,
+    /// Strategy by SKU.
+    pub strategies: HashMap<String, String>,
+}
+
+impl Default for AllocationSettings {
+    fn default() -> Self {
+        Self {
+            default_strategy: "earliest-eta".to_owned(),
+            strategies: HashMap::new(),
+        }
+    }
+}
+
+impl AllocationSettings {
+    pub fn strategies(&self) -> Result<AllocationStrategies, ConfigError> {
+        let strategy = |key: String, name: &str| {
+            allocation::builtin(name).ok_or_else(|| ConfigError::Invalid {
+                key,
+                value: name.to_owned(),
+                reason: format!("expected one of {}", allocation::BUILTIN_NAMES.join(", ")),
+            })
+        };
+        let mut strategies = AllocationStrategies::new(strategy(
+            "allocation.default_strategy".to_owned(),
+            &self.default_strategy,
+        )?);
+        for (sku, name) in &self.strategies {
+            strategies = strategies.with_sku(
+                sku.clone(),
+                strategy(format!("allocation.strategies.{}", sku), name)?,
+            );
+        }
+        Ok(strategies)
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            host: "127.0.0.1".to_owned(),
+            port: 8000,
+            database_url: "sqlite://allocation.db".to_owned(),
+            pool_size: 5,
+            log_level: LogLevel::Info,
+            warehouse_utc_offset: "+00:00".to_owned(),
+            allocation: AllocationSettings::default(),
+        }
+    }
+}
+
+impl Settings {
+    /// Loads the settings from the process environment and the config file
+    /// it names.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let (file, required) = match std::env::var_os("WEBAPP_CONFIG") {
+            Some(path) => (PathBuf::from(path), true),
+            None => (PathBuf::from(DEFAULT_CONFIG_FILE), false),
+        };
+        let file = if required || file.exists() {
+            Some(file)
+        } else {
+            None
+        };
+        Self::load(file.as_deref(), std::env::vars())
+    }
+
+    /// Reads `file`, if any, on top of the defaults, then applies the
+    /// `WEBAPP_*` variables found in `env`.
+    pub fn load(
+        file: Option<&Path>,
+        env: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, ConfigError> {
+        let mut settings = match file {
+            Some(path) => {
+                let contents = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+                    path: path.to_owned(),
+                    source,
+                })?;
+                serde_json::from_str(&contents).map_err(|source| ConfigError::Parse {
+                    path: path.to_owned(),
+                    source,
+                })?
+            }
+            None => Self::default(),
+        };
+        for (key, value) in env {
+            match key.as_str() {
+                "WEBAPP_HOST" => settings.host = value,
+                "WEBAPP_PORT" => settings.port = parse(&key, &value)?,
+                "WEBAPP_DATABASE_URL" => settings.database_url = value,
+                "WEBAPP_POOL_SIZE" => settings.pool_size = parse(&key, &value)?,
+                "WEBAPP_LOG_LEVEL" => settings.log_level = parse(&key, &value)?,
+                "WEBAPP_WAREHOUSE_UTC_OFFSET" => settings.warehouse_utc_offset = value,
+                "WEBAPP_ALLOCATION_STRATEGY" => settings.allocation.default_strategy = value,
+                _ => {}
+            }
+        }
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    pub fn warehouse_offset(&self) -> Result<chrono::FixedOffset, ConfigError> {
+        parse("warehouse_utc_offset", &self.warehouse_utc_offset)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |key: &str, value: String, reason: &str| ConfigError::Invalid {
+            key: key.to_owned(),
+            value,
+            reason: reason.to_owned(),
+        };
+        if self.pool_size == 0 {
+            return Err(invalid("pool_size", "0".to_owned(), "must be at least 1"));
+        }
+        if !self.database_url.starts_with("sqlite:") {
+            return Err(invalid(
+                "database_url",
+                self.database_url.clone(),
+                "must be a sqlite: URL",
+            ));
+        }
+        self.warehouse_offset()?;
+        self.allocation.strategies()?;
+        Ok(())
+    }
+}
+
+fn parse<T>(key: &str, value: &str) -> Result<T, ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    value.parse().map_err(|err: T::Err| ConfigError::Invalid {
+        key: key.to_owned(),
+        value: value.to_owned(),
+        reason: err.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    /// A config file in the temp directory, removed when dropped.
+    struct ConfigFile(PathBuf);
+
+    impl ConfigFile {
+        fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for ConfigFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn config_file(contents: &str) -> ConfigFile {
+        let path = std::env::temp_dir().join(format!(
+            "webapp-config-{}-{:?}.json",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        fs::write(&path, contents).expect("write config file");
+        ConfigFile(path)
+    }
+
+    #[test]
+    fn environment_overrides_the_config_file() {
+        let file = config_file(r#"{ "port": 9000, "pool_size": 2, "log_level": "debug" }"#);
+
+        let settings = Settings::load(
+            Some(file.path()),
+            env(&[("WEBAPP_PORT", "9100"), ("HOME", "/root")]),
+        )
+        .unwrap();
+
+        assert_eq!(settings.port, 9100);
+        assert_eq!(settings.pool_size, 2);
+        assert_eq!(settings.log_level, LogLevel::Debug);
+        assert_eq!(settings.host, Settings::default().host);
+    }
+
+    #[test]
+    fn invalid_environment_values_are_rejected() {
+        let err = Settings::load(None, env(&[("WEBAPP_PORT", "eighty")])).unwrap_err();
+
+        assert!(
+            err.to_string()
+                .starts_with("invalid value 'eighty' for WEBAPP_PORT"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn unknown_keys_in_the_config_file_are_rejected() {
+        let file = config_file(r#"{ "prot": 9000 }"#);
+
+        let err = Settings::load(Some(file.path()), env(&[])).unwrap_err();
+
+        assert!(matches!(err, ConfigError::Parse { .. }), "{}", err);
+    }
+
+    #[test]
+    fn warehouse_utc_offset_is_parsed() {
+        let settings =
+            Settings::load(None, env(&[("WEBAPP_WAREHOUSE_UTC_OFFSET", "+02:00")])).unwrap();
+
+        assert_eq!(
+            settings.warehouse_offset().unwrap(),
+            chrono::FixedOffset::east_opt(2 * 3600).unwrap()
+        );
+        assert!(Settings::load(None, env(&[("WEBAPP_WAREHOUSE_UTC_OFFSET", "CET")])).is_err());
+    }
+
+    #[test]
+    fn allocation_strategies_are_read_per_sku() {
+        let file = config_file(r#"{ "allocation": { "strategies": { "FRESH-MILK": "fefo" } } }"#);
+
+        let settings = Settings::load(
+            Some(file.path()),
+            env(&[("WEBAPP_ALLOCATION_STRATEGY", "best-fit")]),
+        )
+        .unwrap();
+        let strategies = settings.allocation.strategies().unwrap();
+
+        assert_eq!(strategies.for_sku("FRESH-MILK").name(), "fefo");
+        assert_eq!(strategies.for_sku("OTHER").name(), "best-fit");
+    }
+
+    #[test]
+    fn unknown_allocation_strategies_are_rejected() {
+        let file =
+            config_file(r#"{ "allocation": { "strategies": { "FRESH-MILK": "freshest" } } }"#);
+
+        let err = Settings::load(Some(file.path()), env(&[])).unwrap_err();
+
+        assert!(
+            err.to_string()
+                .starts_with("invalid value 'freshest' for allocation.strategies.FRESH-MILK"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn zero_pool_size_is_rejected() {
+        let err = Settings::load(None, env(&[("WEBAPP_POOL_SIZE", "0")])).unwrap_err();
+
+        assert!(matches!(err, ConfigError::Invalid { .. }), "{}", err);
+    }
+}
@@ -0,0 +1,196 @@
+use axum::{
+    extract::rejection::{JsonRejection, QueryRejection},
+    http::header,
+    response::{IntoResponse, Response},
+};
+use domain::repository::RepositoryError;
+
+use crate::problem::{Problem, ProblemType};
+
+/// Error returned by the route handlers, rendered as an RFC 7807
+/// `application/problem+json` document.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("{detail}")]
+    Validation {
+        field: Option<String>,
+        detail: String,
+    },
+    #[error("Unknown order '{0}'")]
+    UnknownOrder(String),
+    #[error("Unknown batch '{0}'")]
+    UnknownBatch(String),
+    #[error(transparent)]
+    Service(#[from] service_layer::Error),
+    #[error(transparent)]
+    Storage(#[from] infrastructure::Error),
+}
+
+impl Error {
+    pub fn invalid_field(field: &str, detail: impl Into<String>) -> Self {
+        Self::Validation {
+            field: Some(field.to_owned()),
+            detail: detail.into(),
+        }
+    }
+
+    fn problem(&self) -> Problem {
+        use service_layer::Error as ServiceError;
+        // The cause of a server error is logged, not shown: it may reveal
+        // the queries and schema of the database.
+        let problem = |kind: ProblemType| {
+            let detail = if kind.status().is_server_error() {
+                "The request could not be completed; try again later.".to_owned()
+            } else {
+                self.to_string()
+            };
+            Problem::new(kind, detail)
+        };
+        match self {
+            Self::Validation { field, .. } => {
+                problem(ProblemType::ValidationFailed).with_field(field.clone())
+            }
+            Self::UnknownOrder(orderid) => problem(ProblemType::NotFound).with_orderid(orderid),
+            Self::UnknownBatch(reference) => {
+                problem(ProblemType::NotFound).with_reference(reference)
+            }
+            Self::Storage(err) => match err {
+                infrastructure::Error::NotFound(_) => problem(ProblemType::NotFound),
+                infrastructure::Error::Conflict { sku, .. } => {
+                    problem(ProblemType::Conflict).with_sku(sku)
+                }
+                infrastructure::Error::Constraint {
+                    kind: infrastructure::ConstraintKind::Unique,
+                    ..
+                } => problem(ProblemType::Duplicate),
+                infrastructure::Error::Constraint { .. } => problem(ProblemType::ValidationFailed),
+                infrastructure::Error::Locked(_) => problem(ProblemType::Conflict),
+                infrastructure::Error::Database(_) => problem(ProblemType::Unavailable),
+                infrastructure::Error::Decode(_) => problem(ProblemType::Internal),
+            },
+            Self::Service(err) => match err {
+                ServiceError::InvalidSku(sku) => problem(ProblemType::InvalidSku).with_sku(sku),
+                ServiceError::Domain(domain::Error::OutOfStock(sku)) => {
+                    problem(ProblemType::OutOfStock).with_sku(sku)
+                }
+                ServiceError::UnknownBatch(reference) => {
+                    problem(ProblemType::NotFound).with_reference(reference)
+                }
+                ServiceError::DuplicateBatch(reference) => {
+                    problem(ProblemType::Duplicate).with_reference(reference)
+                }
+                ServiceError::NotAllocated { orderid, sku } => problem(ProblemType::NotFound)
+                    .with_orderid(orderid)
+                    .with_sku(sku),
+                ServiceError::Repository(err) => match err {
+                    RepositoryError::Concurrency { sku, .. } => {
+                        problem(ProblemType::Conflict).with_sku(sku)
+                    }
+                    RepositoryError::Busy(_) => problem(ProblemType::Conflict),
+                    RepositoryError::NotFound(_) => problem(ProblemType::NotFound),
+                    RepositoryError::Duplicate(_) => problem(ProblemType::Duplicate),
+                    RepositoryError::Rejected(_) => problem(ProblemType::ValidationFailed),
+                    RepositoryError::Unavailable(_) => problem(ProblemType::Unavailable),
+                    RepositoryError::Corrupt(_) => problem(ProblemType::Internal),
+                },
+            },
+        }
+    }
+}
+
+impl From<JsonRejection> for Error {
+    fn from(rejection: JsonRejection) -> Self {
+        Self::rejected(&rejection)
+    }
+}
+
+impl From<QueryRejection> for Error {
+    fn from(rejection: QueryRejection) -> Self {
+        Self::rejected(&rejection)
+    }
+}
+
+impl Error {
+    /// Describes a rejected request with the messages of the whole error
+    /// chain, since axum only displays a generic summary.
+    fn rejected(rejection: &dyn std::error::Error) -> Self {
+        let mut detail = rejection.to_string();
+        let mut source = rejection.source();
+        while let Some(cause) = source {
+            let message = cause.to_string();
+            if !detail.contains(&message) {
+                detail = format!("{}: {}", detail, message);
+            }
+            source = cause.source();
+        }
+        Self::Validation {
+            field: offending_field(&detail),
+            detail,
+        }
+    }
+}
+
+/// Picks the field name out of serde messages such as "missing field `qty`".
+fn offending_field(message: &str) -> Option<String> {
+    let start = message.find("field `")? + "field `".len();
+    let len = message[start..].find('`')?;
+    Some(message[start..start + len].to_owned())
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let problem = self.problem();
+        if problem.status.is_server_error() {
+            log::error!("{}", self);
+        }
+        (
+            problem.status,
+            [(header::CONTENT_TYPE, "application/problem+json")],
+            serde_json::to_string(&problem).expect("problem serializes to json"),
+        )
+            .into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+
+    use super::*;
+
+    #[test]
+    fn offending_field_is_read_from_serde_messages() {
+        assert_eq!(
+            offending_field("missing field `qty` at line 1 column 20").as_deref(),
+            Some("qty")
+        );
+        assert_eq!(offending_field("expected value at line 1 column 1"), None);
+    }
+
+    #[test]
+    fn server_errors_do_not_reveal_their_cause() {
+        let err = Error::from(service_layer::Error::Repository(
+            RepositoryError::Unavailable("no such table: allocations".to_owned()),
+        ));
+
+        let problem = err.problem();
+
+        assert_eq!(problem.code, "unavailable");
+        assert!(
+            !problem.detail.contains("allocations"),
+            "{}",
+            problem.detail
+        );
+    }
+
+    #[test]
+    fn status_matches_the_problem_type() {
+        let err = Error::from(service_layer::Error::Repository(
+            RepositoryError::Unavailable("locked".to_owned()),
+        ));
+        assert_eq!(
+            err.into_response().status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+}
@@ -0,0 +1,36 @@
+//! Event handlers wired into the message bus by [`crate::startup`].
+use async_trait::async_trait;
+use domain::{events::Event, repository::RepositoryError, unit_of_work::UnitOfWork};
+use infrastructure::{unit_of_work::SqlxUnitOfWork, views};
+use service_layer::{Error, EventHandler};
+
+/// Keeps `allocations_view` in step with `Allocated` and `Deallocated`,
+/// writing in the transaction of the unit of work it is given.
+pub struct UpdateAllocationsView;
+
+#[async_trait]
+impl EventHandler<SqlxUnitOfWork> for UpdateAllocationsView {
+    async fn handle(&self, event: &Event, uow: &mut SqlxUnitOfWork) -> Result<(), Error> {
+        {
+            let mut conn = uow.connection().await.map_err(RepositoryError::from)?;
+            let res = match event {
+                Event::Allocated {
+                    orderid,
+                    sku,
+                    qty,
+                    batchref,
+                } => views::add_allocation(&mut conn, orderid, sku, *qty, batchref).await,
+                Event::Deallocated {
+                    orderid,
+                    sku,
+                    qty,
+                    batchref,
+                } => views::remove_allocation(&mut conn, orderid, sku, *qty, batchref).await,
+                _ => Ok(()),
+            };
+            res.map_err(RepositoryError::from)?;
+        }
+        uow.commit().await?;
+        Ok(())
+    }
+}
@@ -1,2 +1,9 @@
+pub mod configuration;
+mod error;
+pub mod handlers;
+pub mod logging;
+pub mod problem;
 pub mod routes;
 pub mod startup;
+
+pub use error::Error;
@@ -0,0 +1,52 @@
+//! Logs of the webapp and its libraries, written to stderr through the
+//! `log` facade and filtered by the configured [`LogLevel`].
+use crate::configuration::LogLevel;
+
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{} {}: {}", record.level(), record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+impl From<LogLevel> for log::LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => Self::Error,
+            LogLevel::Warn => Self::Warn,
+            LogLevel::Info => Self::Info,
+            LogLevel::Debug => Self::Debug,
+        }
+    }
+}
+
+/// Logs records up to `level` from now on; only the first call installs
+/// the logger, later ones change the level.
+pub fn init(level: LogLevel) {
+    static LOGGER: StderrLogger = StderrLogger;
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(level.into());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn configured_levels_filter_less_severe_records() {
+        init(LogLevel::Warn);
+
+        assert!(log::log_enabled!(log::Level::Error));
+        assert!(log::log_enabled!(log::Level::Warn));
+        assert!(!log::log_enabled!(log::Level::Info));
+    }
+}
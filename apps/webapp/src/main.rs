@@ -1,3 +1,67 @@
-fn main() {
-    println!("Hello, world!");
+use std::{net::TcpListener, process, sync::Arc};
+
+use domain::clock::SystemClock;
+
+use sqlx::sqlite::SqlitePoolOptions;
+use webapp::{configuration::Settings, logging, startup};
+
+/// Serves the API; `webapp rebuild-allocations-view` instead recomputes the
+/// read model from the write tables and exits.
+#[tokio::main]
+async fn main() {
+    let settings = Settings::from_env().unwrap_or_else(|err| {
+        eprintln!("webapp: invalid configuration: {}", err);
+        process::exit(1);
+    });
+    logging::init(settings.log_level);
+    let strategies = settings.allocation.strategies().unwrap_or_else(|err| {
+        log::error!("invalid configuration: {}", err);
+        process::exit(1);
+    });
+    let clock = settings
+        .warehouse_offset()
+        .map(SystemClock::with_offset)
+        .unwrap_or_else(|err| {
+            log::error!("invalid configuration: {}", err);
+            process::exit(1);
+        });
+    let options = infrastructure::connect_options(&settings.database_url).unwrap_or_else(|err| {
+        log::error!("invalid database_url: {}", err);
+        process::exit(1);
+    });
+    let db_pool = SqlitePoolOptions::new()
+        .max_connections(settings.pool_size)
+        .connect_with(options)
+        .await
+        .unwrap_or_else(|err| {
+            log::error!("cannot open {}: {}", settings.database_url, err);
+            process::exit(1);
+        });
+    if let Err(err) = infrastructure::run_migrations(&db_pool).await {
+        log::error!("running migrations: {}", err);
+        process::exit(1);
+    }
+    if std::env::args().nth(1).as_deref() == Some("rebuild-allocations-view") {
+        match infrastructure::views::rebuild_allocations_view(&db_pool).await {
+            Ok(rows) => log::info!("rebuilt allocations_view with {} rows", rows),
+            Err(err) => {
+                log::error!("rebuilding allocations_view: {}", err);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+    let listener = TcpListener::bind(settings.address()).unwrap_or_else(|err| {
+        log::error!("cannot bind {}: {}", settings.address(), err);
+        process::exit(1);
+    });
+    log::info!(
+        "listening on {} (log level {})",
+        settings.address(),
+        settings.log_level
+    );
+    if let Err(err) = startup::run(listener, db_pool, strategies, Arc::new(clock)).await {
+        log::error!("{}", err);
+        process::exit(1);
+    }
 }
@@ -0,0 +1,113 @@
+//! RFC 7807 problem details and the catalogue of problem types the API
+//! returns. The `code` of a type is stable: clients may match on it.
+use axum::http::StatusCode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProblemType {
+    OutOfStock,
+    InvalidSku,
+    ValidationFailed,
+    Conflict,
+    Duplicate,
+    NotFound,
+    Unavailable,
+    Internal,
+}
+
+impl ProblemType {
+    pub fn code(self) -> &'static str {
+        match self {
+            Self::OutOfStock => "out-of-stock",
+            Self::InvalidSku => "invalid-sku",
+            Self::ValidationFailed => "validation-failed",
+            Self::Conflict => "conflict",
+            Self::Duplicate => "duplicate",
+            Self::NotFound => "not-found",
+            Self::Unavailable => "unavailable",
+            Self::Internal => "internal",
+        }
+    }
+
+    pub fn title(self) -> &'static str {
+        match self {
+            Self::OutOfStock => "Out of stock",
+            Self::InvalidSku => "Invalid SKU",
+            Self::ValidationFailed => "Validation failed",
+            Self::Conflict => "Conflicting concurrent update",
+            Self::Duplicate => "Already exists",
+            Self::NotFound => "Not found",
+            Self::Unavailable => "Service unavailable",
+            Self::Internal => "Internal error",
+        }
+    }
+
+    pub fn status(self) -> StatusCode {
+        match self {
+            Self::OutOfStock | Self::InvalidSku | Self::ValidationFailed => StatusCode::BAD_REQUEST,
+            Self::Conflict | Self::Duplicate => StatusCode::CONFLICT,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Body of an `application/problem+json` response.
+#[derive(Debug, serde::Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub type_uri: String,
+    pub code: &'static str,
+    pub title: &'static str,
+    #[serde(serialize_with = "serialize_status")]
+    pub status: StatusCode,
+    pub detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub field: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sku: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub orderid: Option<String>,
+}
+
+impl Problem {
+    pub fn new(kind: ProblemType, detail: String) -> Self {
+        Self {
+            type_uri: format!("/problems/{}", kind.code()),
+            code: kind.code(),
+            title: kind.title(),
+            status: kind.status(),
+            detail,
+            field: None,
+            sku: None,
+            reference: None,
+            orderid: None,
+        }
+    }
+
+    pub fn with_field(mut self, field: Option<String>) -> Self {
+        self.field = field;
+        self
+    }
+
+    pub fn with_sku(mut self, sku: &str) -> Self {
+        self.sku = Some(sku.to_owned());
+        self
+    }
+
+    pub fn with_reference(mut self, reference: &str) -> Self {
+        self.reference = Some(reference.to_owned());
+        self
+    }
+
+    pub fn with_orderid(mut self, orderid: &str) -> Self {
+        self.orderid = Some(orderid.to_owned());
+        self
+    }
+}
+
+fn serialize_status<S: serde::Serializer>(status: &StatusCode, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_u16(status.as_u16())
+}
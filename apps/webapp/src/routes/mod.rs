@@ -1,27 +1,333 @@
-use axum::extract::{Extension, Json};
-use axum::http::StatusCode;
-use domain::model;
-use infrastructure::repositories;
-use sqlx::SqlitePool;
+use std::sync::Arc;
+
+use axum::extract::{
+    rejection::{JsonRejection, QueryRejection},
+    Extension, Json, Path, Query,
+};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use domain::{clock::Clock, commands::Command, repository::Repository};
+use infrastructure::{
+    repositories::{BatchFilter, SqlxRepository},
+    unit_of_work::SqlxUnitOfWorkFactory,
+    views,
+};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use service_layer::MessageBus;
+use sqlx::sqlite::SqlitePool;
+
+use crate::Error;
 
 #[derive(serde::Deserialize)]
 pub struct Allocate {
     pub orderid: String,
     pub sku: String,
     pub qty: u32,
+    /// Whether the line may be split across several batches.
+    #[serde(default)]
+    pub split: bool,
 }
 
 pub async fn allocate(
-    Json(data): Json<Allocate>,
-    Extension(db_pool): Extension<SqlitePool>,
-) -> (StatusCode, Json<serde_json::Value>) {
-    let repo = repositories::SqlxRepository::new(db_pool);
-    let mut batches = repo.list().await;
-
-    let line = model::OrderLine::new(data.orderid, data.sku, data.qty);
-    let batchref = model::allocate(line, &mut batches).expect("");
-    (
+    payload: Result<Json<Allocate>, JsonRejection>,
+    Extension(bus): Extension<Arc<MessageBus<SqlxUnitOfWorkFactory>>>,
+) -> Result<(StatusCode, Json<serde_json::Value>), Error> {
+    let Json(data) = payload?;
+    if data.qty == 0 {
+        return Err(Error::invalid_field("qty", "qty must be greater than zero"));
+    }
+    if data.split {
+        let command = Command::AllocateSplit {
+            orderid: data.orderid,
+            sku: data.sku,
+            qty: data.qty,
+        };
+        let allocations: Vec<_> = bus
+            .handle(command)
+            .await?
+            .fragments()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(batchref, qty)| serde_json::json!({ "batchref": batchref, "qty": qty }))
+            .collect();
+        return Ok((
+            StatusCode::CREATED,
+            Json(serde_json::json!({ "allocations": allocations })),
+        ));
+    }
+    let command = Command::Allocate {
+        orderid: data.orderid,
+        sku: data.sku,
+        qty: data.qty,
+    };
+    let batchref = bus.handle(command).await?.batchref();
+    Ok((
         StatusCode::CREATED,
         Json(serde_json::json!({ "batchref": batchref })),
-    )
+    ))
+}
+
+#[derive(serde::Deserialize)]
+pub struct Deallocate {
+    pub orderid: String,
+    pub sku: String,
+    pub qty: u32,
+}
+
+pub async fn deallocate(
+    payload: Result<Json<Deallocate>, JsonRejection>,
+    Extension(bus): Extension<Arc<MessageBus<SqlxUnitOfWorkFactory>>>,
+) -> Result<(StatusCode, Json<serde_json::Value>), Error> {
+    let Json(data) = payload?;
+    if data.qty == 0 {
+        return Err(Error::invalid_field("qty", "qty must be greater than zero"));
+    }
+    let command = Command::Deallocate {
+        orderid: data.orderid,
+        sku: data.sku,
+        qty: data.qty,
+    };
+    let batchref = bus.handle(command).await?.batchref();
+    Ok((
+        StatusCode::OK,
+        Json(serde_json::json!({ "batchref": batchref })),
+    ))
+}
+
+#[derive(serde::Deserialize)]
+pub struct ChangeBatchQuantity {
+    pub batchref: String,
+    pub qty: u32,
+}
+
+pub async fn change_batch_quantity(
+    payload: Result<Json<ChangeBatchQuantity>, JsonRejection>,
+    Extension(bus): Extension<Arc<MessageBus<SqlxUnitOfWorkFactory>>>,
+) -> Result<(StatusCode, Json<serde_json::Value>), Error> {
+    let Json(data) = payload?;
+    if data.qty == 0 {
+        return Err(Error::invalid_field("qty", "qty must be greater than zero"));
+    }
+    let command = Command::ChangeBatchQuantity {
+        reference: data.batchref.clone(),
+        qty: data.qty,
+    };
+    bus.handle(command).await?;
+    Ok((
+        StatusCode::OK,
+        Json(serde_json::json!({ "batchref": data.batchref, "qty": data.qty })),
+    ))
+}
+
+#[derive(serde::Deserialize)]
+pub struct AddBatch {
+    pub reference: String,
+    pub sku: String,
+    pub qty: u32,
+    pub eta: Option<String>,
+    pub expiry: Option<String>,
+}
+
+/// Characters escaped in a path segment: those that would end it or make it
+/// ambiguous, besides controls and non-ASCII.
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+pub async fn add_batch(
+    payload: Result<Json<AddBatch>, JsonRejection>,
+    Extension(bus): Extension<Arc<MessageBus<SqlxUnitOfWorkFactory>>>,
+) -> Result<(StatusCode, HeaderMap, Json<serde_json::Value>), Error> {
+    let Json(data) = payload?;
+    if data.reference.is_empty() {
+        return Err(Error::invalid_field(
+            "reference",
+            "reference must not be empty",
+        ));
+    }
+    let eta = data
+        .eta
+        .as_deref()
+        .map(|eta| parse_date("eta", eta))
+        .transpose()?;
+    let expiry = data
+        .expiry
+        .as_deref()
+        .map(|expiry| parse_date("expiry", expiry))
+        .transpose()?;
+    let command = Command::CreateBatch {
+        reference: data.reference.clone(),
+        sku: data.sku.clone(),
+        qty: data.qty,
+        eta,
+        expiry,
+    };
+    bus.handle(command).await?;
+    let mut headers = HeaderMap::new();
+    let location = format!(
+        "/batches/{}",
+        utf8_percent_encode(&data.reference, PATH_SEGMENT)
+    );
+    headers.insert(
+        header::LOCATION,
+        HeaderValue::from_str(&location).expect("a percent-encoded path is a valid header"),
+    );
+    Ok((
+        StatusCode::CREATED,
+        headers,
+        Json(serde_json::json!({
+            "reference": data.reference,
+            "sku": data.sku,
+            "qty": data.qty,
+            "eta": eta.map(format_date),
+            "expiry": expiry.map(format_date),
+        })),
+    ))
+}
+
+pub async fn get_batch(
+    Path(reference): Path<String>,
+    Extension(db_pool): Extension<SqlitePool>,
+) -> Result<Json<serde_json::Value>, Error> {
+    let batch = SqlxRepository::new(db_pool)
+        .get(&reference)
+        .await
+        .map_err(service_layer::Error::from)?
+        .ok_or(Error::UnknownBatch(reference))?;
+    Ok(Json(serde_json::json!({
+        "reference": batch.reference(),
+        "sku": batch.sku(),
+        "eta": batch.eta().map(format_date),
+        "expiry": batch.expiry().map(format_date),
+        "purchased_quantity": batch.purchased_quantity(),
+        "allocated_quantity": batch.allocated_quantity(),
+        "available_quantity": batch.available_quantity(),
+    })))
+}
+
+pub async fn allocations(
+    Path(orderid): Path<String>,
+    Extension(db_pool): Extension<SqlitePool>,
+) -> Result<Json<serde_json::Value>, Error> {
+    let allocations = views::allocations(&db_pool, &orderid).await?;
+    if allocations.is_empty() {
+        return Err(Error::UnknownOrder(orderid));
+    }
+    let allocations: Vec<_> = allocations
+        .into_iter()
+        .map(|allocation| {
+            serde_json::json!({
+                "sku": allocation.sku,
+                "qty": allocation.qty,
+                "batchref": allocation.batchref,
+                "eta": allocation.eta.map(format_date),
+            })
+        })
+        .collect();
+    Ok(Json(serde_json::json!(allocations)))
+}
+
+/// Largest page `GET /batches` returns, and the default.
+pub const MAX_PAGE_SIZE: u32 = 100;
+
+#[derive(serde::Deserialize)]
+pub struct ListBatches {
+    pub sku: Option<String>,
+    pub eta_from: Option<String>,
+    pub eta_to: Option<String>,
+    #[serde(default)]
+    pub in_stock: bool,
+    #[serde(default)]
+    pub has_availability: bool,
+    pub cursor: Option<String>,
+    pub limit: Option<u32>,
+}
+
+pub async fn list_batches(
+    query: Result<Query<ListBatches>, QueryRejection>,
+    Extension(db_pool): Extension<SqlitePool>,
+    Extension(clock): Extension<Arc<dyn Clock>>,
+) -> Result<Json<serde_json::Value>, Error> {
+    let Query(query) = query?;
+    let limit = query.limit.unwrap_or(MAX_PAGE_SIZE);
+    if limit == 0 || limit > MAX_PAGE_SIZE {
+        return Err(Error::invalid_field(
+            "limit",
+            format!("limit must be between 1 and {}", MAX_PAGE_SIZE),
+        ));
+    }
+    let after = query.cursor.as_deref().map(decode_cursor).transpose()?;
+    let filter = BatchFilter {
+        sku: query.sku,
+        eta_from: query
+            .eta_from
+            .as_deref()
+            .map(|eta| parse_date("eta_from", eta))
+            .transpose()?,
+        eta_to: query
+            .eta_to
+            .as_deref()
+            .map(|eta| parse_date("eta_to", eta))
+            .transpose()?,
+        in_stock_on: query.in_stock.then(|| clock.today()),
+        has_availability: query.has_availability,
+    };
+    let page = SqlxRepository::new(db_pool)
+        .list_page(&filter, after, limit)
+        .await?;
+    let batches: Vec<_> = page
+        .batches
+        .iter()
+        .map(|batch| {
+            serde_json::json!({
+                "reference": batch.reference,
+                "sku": batch.sku,
+                "eta": batch.eta.map(format_date),
+                "purchased_quantity": batch.purchased_quantity,
+                "allocated_quantity": batch.allocated_quantity,
+                "available_quantity": batch.available_quantity(),
+            })
+        })
+        .collect();
+    Ok(Json(serde_json::json!({
+        "batches": batches,
+        "next_cursor": page.next_after.map(encode_cursor),
+    })))
+}
+
+/// Dates are exchanged as ISO-8601 calendar dates, like the database stores
+/// them.
+const DATE_FORMAT: &str = "%Y-%m-%d";
+
+fn parse_date(field: &str, date: &str) -> Result<chrono::NaiveDate, Error> {
+    chrono::NaiveDate::parse_from_str(date, DATE_FORMAT)
+        .map_err(|_| Error::invalid_field(field, format!("{} must be a YYYY-MM-DD date", field)))
+}
+
+fn format_date(date: chrono::NaiveDate) -> String {
+    date.format(DATE_FORMAT).to_string()
+}
+
+const CURSOR_PREFIX: &str = "batch:";
+
+fn encode_cursor(after: u32) -> String {
+    URL_SAFE_NO_PAD.encode(format!("{}{}", CURSOR_PREFIX, after))
+}
+
+fn decode_cursor(cursor: &str) -> Result<u32, Error> {
+    URL_SAFE_NO_PAD
+        .decode(cursor)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .and_then(|cursor| cursor.strip_prefix(CURSOR_PREFIX)?.parse().ok())
+        .ok_or_else(|| Error::invalid_field("cursor", "cursor is not one this API returned"))
 }
@@ -1,15 +1,43 @@
 use sqlx::sqlite::SqlitePool;
-use std::net::TcpListener;
+use std::{net::TcpListener, sync::Arc};
 
 use axum::{extract::Extension, Router};
+use domain::{allocation::AllocationStrategies, clock::Clock};
+use infrastructure::unit_of_work::SqlxUnitOfWorkFactory;
+use service_layer::MessageBus;
 
-use crate::routes;
+use crate::{handlers::UpdateAllocationsView, routes};
 
-pub async fn run(listener: TcpListener, db_pool: SqlitePool) -> std::io::Result<()> {
-    println!("webapp::startup::run()");
+pub async fn run(
+    listener: TcpListener,
+    db_pool: SqlitePool,
+    strategies: AllocationStrategies,
+    clock: Arc<dyn Clock>,
+) -> std::io::Result<()> {
+    log::debug!("starting on {:?}", listener.local_addr());
     use axum::routing::{get, post};
+    let bus = Arc::new(
+        MessageBus::new(SqlxUnitOfWorkFactory::new(db_pool.clone()))
+            .with_event_handler("Allocated", UpdateAllocationsView)
+            .with_event_handler("Deallocated", UpdateAllocationsView)
+            .with_strategies(strategies)
+            .with_clock(clock.clone()),
+    );
     let app = Router::new()
         .route("/allocate", post(routes::allocate))
+        .route(
+            "/batches",
+            get(routes::list_batches).post(routes::add_batch),
+        )
+        .route("/batches/:reference", get(routes::get_batch))
+        .route("/deallocate", post(routes::deallocate))
+        .route("/allocations/:orderid", get(routes::allocations))
+        .route(
+            "/change_batch_quantity",
+            post(routes::change_batch_quantity),
+        )
+        .layer(Extension(bus))
+        .layer(Extension(clock))
         .layer(Extension(db_pool));
     axum::Server::from_tcp(listener)
         .expect("Failed binding")
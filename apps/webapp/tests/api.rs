@@ -1,8 +1,9 @@
-use sqlx::{sqlite::SqlitePool, Row};
-use std::{
-    collections::{HashMap, HashSet},
-    net::TcpListener,
+use domain::clock::{Clock, ManualClock};
+use sqlx::{
+    sqlite::{SqlitePool, SqlitePoolOptions},
+    Row,
 };
+use std::{collections::HashMap, net::TcpListener, sync::Arc};
 use webapp::startup;
 
 fn random_suffix() -> String {
@@ -27,41 +28,22 @@ fn random_orderid(name: &str) -> String {
     format!("order-{}-{}", name, random_suffix())
 }
 
-async fn add_stock(
-    session: &SqlitePool,
-    lines: &[(String, String, u32, Option<&str>)],
-) -> (HashSet<u32>, HashSet<String>) {
-    const INSERT_BATCHES: &str = "
-        INSERT INTO batches (reference, sku, _purchased_quantity, eta)
-        VALUES ($1, $2, $3, $4)
-    ";
-    const SELECT_BATCH: &str = "
-        SELECT id 
-        FROM batches 
-        WHERE reference=$1 AND sku=$2
-    ";
-    let mut batches_added = HashSet::new();
-    let mut sku_added = HashSet::new();
+async fn add_stock(app: &TestApp, lines: &[(String, String, u32, Option<&str>)]) {
+    let client = reqwest::Client::new();
     for (reference, sku, qty, eta) in lines {
-        sqlx::query(INSERT_BATCHES)
-            .bind(&reference)
-            .bind(&sku)
-            .bind(qty)
-            .bind(eta)
-            .execute(session)
-            .await
-            .expect("insert batch");
-        let row = sqlx::query(SELECT_BATCH)
-            .bind(&reference)
-            .bind(&sku)
-            .fetch_one(session)
+        let response = client
+            .post(format!("{}/batches", &app.address))
+            .json(&serde_json::json!({
+                "reference": reference,
+                "sku": sku,
+                "qty": qty,
+                "eta": eta,
+            }))
+            .send()
             .await
-            .expect("select batch");
-        let batch_id: u32 = row.try_get("id").expect("get id");
-        batches_added.insert(batch_id);
-        sku_added.insert(sku.clone());
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201, "add batch {}", reference);
     }
-    (batches_added, sku_added)
 }
 
 #[tokio::test]
@@ -73,8 +55,8 @@ async fn api_returns_allocation() {
     let laterbatch = random_batchref("2");
     let otherbatch = random_batchref("3");
     let app = spawn_app().await;
-    let (batches_added, sku_added) = add_stock(
-        &app.db_pool,
+    add_stock(
+        &app,
         &[
             (laterbatch, sku.clone(), 100, Some("2011-01-02")),
             (earlybatch.clone(), sku.clone(), 100, Some("2011-01-01")),
@@ -106,17 +88,937 @@ async fn api_returns_allocation() {
     assert_eq!(response_json["batchref"], earlybatch);
 }
 
+#[tokio::test]
+async fn api_adds_a_batch() {
+    // Arrange
+    let app = spawn_app().await;
+    let batchref = random_batchref("1");
+    let sku = random_sku("");
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/batches", &app.address))
+        .json(&serde_json::json!({
+            "reference": batchref,
+            "sku": sku,
+            "qty": 20,
+            "eta": "2011-01-02",
+            "expiry": "2011-02-01",
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 201);
+    assert_eq!(
+        response.headers()[reqwest::header::LOCATION],
+        format!("/batches/{}", batchref)
+    );
+    let body = response
+        .json::<serde_json::Value>()
+        .await
+        .expect("Failed to parse response");
+    assert_eq!(body["eta"], "2011-01-02");
+    assert_eq!(body["expiry"], "2011-02-01");
+    let row = sqlx::query("SELECT sku, _purchased_quantity, eta FROM batches WHERE reference = $1")
+        .bind(&batchref)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("select batch");
+    let stored_sku: String = row.try_get("sku").expect("get sku");
+    let qty: u32 = row.try_get("_purchased_quantity").expect("get qty");
+    let eta: Option<chrono::NaiveDate> = row.try_get("eta").expect("get eta");
+    assert_eq!(stored_sku, sku);
+    assert_eq!(qty, 20);
+    assert_eq!(eta, chrono::NaiveDate::from_ymd_opt(2011, 1, 2));
+    let stored: String = sqlx::query("SELECT expiry FROM batches WHERE reference = $1")
+        .bind(&batchref)
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("select expiry")
+        .get("expiry");
+    assert_eq!(stored, "2011-02-01");
+}
+
+#[tokio::test]
+async fn api_serves_a_batch_at_its_location() {
+    // Arrange
+    let app = spawn_app().await;
+    let batchref = format!("{} / #1", random_batchref(""));
+    let sku = random_sku("");
+    let client = reqwest::Client::new();
+    let response = client
+        .post(format!("{}/batches", &app.address))
+        .json(&serde_json::json!({
+            "reference": batchref,
+            "sku": sku,
+            "qty": 20,
+            "eta": "2011-01-02",
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request");
+    assert_eq!(response.status().as_u16(), 201);
+    let location = response.headers()[reqwest::header::LOCATION]
+        .to_str()
+        .expect("Location is ASCII")
+        .to_owned();
+    assert_eq!(
+        location,
+        format!(
+            "/batches/{}",
+            batchref
+                .replace(' ', "%20")
+                .replace('/', "%2F")
+                .replace('#', "%23")
+        )
+    );
+
+    // Act
+    let response = client
+        .get(format!("{}{}", &app.address, location))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let body = response
+        .json::<serde_json::Value>()
+        .await
+        .expect("Failed to parse response");
+    assert_eq!(
+        body,
+        serde_json::json!({
+            "reference": batchref,
+            "sku": sku,
+            "eta": "2011-01-02",
+            "expiry": null,
+            "purchased_quantity": 20,
+            "allocated_quantity": 0,
+            "available_quantity": 20,
+        })
+    );
+}
+
+#[tokio::test]
+async fn api_returns_404_for_unknown_batch() {
+    // Arrange
+    let app = spawn_app().await;
+    let batchref = random_batchref("");
+
+    // Act
+    let response = reqwest::Client::new()
+        .get(format!("{}/batches/{}", &app.address, batchref))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 404);
+    let problem = problem(response).await;
+    assert_eq!(problem["code"], "not-found");
+    assert_eq!(problem["reference"], batchref);
+}
+
+#[tokio::test]
+async fn api_returns_409_for_duplicate_batch_reference() {
+    // Arrange
+    let app = spawn_app().await;
+    let batchref = random_batchref("1");
+    add_stock(&app, &[(batchref.clone(), random_sku(""), 20, None)]).await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/batches", &app.address))
+        .json(&serde_json::json!({
+            "reference": batchref,
+            "sku": random_sku("other"),
+            "qty": 10,
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 409);
+    let problem = problem(response).await;
+    assert_eq!(problem["code"], "duplicate");
+    assert_eq!(problem["reference"], batchref);
+}
+
+#[tokio::test]
+async fn api_returns_validation_problem_for_malformed_eta() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/batches", &app.address))
+        .json(&serde_json::json!({
+            "reference": random_batchref("1"),
+            "sku": random_sku(""),
+            "qty": 10,
+            "eta": "next tuesday",
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+    assert_eq!(problem(response).await["field"], "eta");
+}
+
+#[tokio::test]
+async fn api_deallocates_an_allocated_line() {
+    // Arrange
+    let sku = random_sku("");
+    let batchref = random_batchref("1");
+    let orderid = random_orderid("");
+    let app = spawn_app().await;
+    add_stock(&app, &[(batchref.clone(), sku.clone(), 10, None)]).await;
+    let client = reqwest::Client::new();
+    let line = serde_json::json!({ "orderid": orderid, "sku": sku, "qty": 10 });
+    let response = client
+        .post(format!("{}/allocate", &app.address))
+        .json(&line)
+        .send()
+        .await
+        .expect("Failed to execute request");
+    assert_eq!(response.status().as_u16(), 201);
+
+    // Act
+    let response = client
+        .post(format!("{}/deallocate", &app.address))
+        .json(&line)
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let response_json = response
+        .json::<HashMap<String, String>>()
+        .await
+        .expect("Failed to parse json");
+    assert_eq!(response_json["batchref"], batchref);
+    let response = client
+        .get(format!("{}/allocations/{}", &app.address, orderid))
+        .send()
+        .await
+        .expect("Failed to execute request");
+    assert_eq!(response.status().as_u16(), 404);
+    assert_eq!(post_allocate(&app, &sku, 10).await.status().as_u16(), 201);
+}
+
+#[tokio::test]
+async fn api_returns_404_when_deallocating_an_unknown_line() {
+    // Arrange
+    let sku = random_sku("");
+    let orderid = random_orderid("");
+    let app = spawn_app().await;
+    add_stock(&app, &[(random_batchref("1"), sku.clone(), 10, None)]).await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/deallocate", &app.address))
+        .json(&serde_json::json!({ "orderid": orderid, "sku": sku, "qty": 10 }))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 404);
+    let problem = problem(response).await;
+    assert_eq!(problem["code"], "not-found");
+    assert_eq!(problem["orderid"], orderid);
+    assert_eq!(problem["sku"], sku);
+}
+
+#[tokio::test]
+async fn api_lists_the_allocations_of_an_order() {
+    // Arrange
+    let sku = random_sku("");
+    let othersku = random_sku("other");
+    let batchref = random_batchref("1");
+    let otherbatch = random_batchref("2");
+    let orderid = random_orderid("");
+    let app = spawn_app().await;
+    add_stock(
+        &app,
+        &[
+            (batchref.clone(), sku.clone(), 10, Some("2011-01-02")),
+            (otherbatch.clone(), othersku.clone(), 10, None),
+        ],
+    )
+    .await;
+    let client = reqwest::Client::new();
+    for (sku, qty) in [(&sku, 3), (&othersku, 4)] {
+        let response = client
+            .post(format!("{}/allocate", &app.address))
+            .json(&serde_json::json!({ "orderid": orderid, "sku": sku, "qty": qty }))
+            .send()
+            .await
+            .expect("Failed to execute request");
+        assert_eq!(response.status().as_u16(), 201);
+    }
+
+    // Act
+    let response = client
+        .get(format!("{}/allocations/{}", &app.address, orderid))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let mut allocations = response
+        .json::<Vec<serde_json::Value>>()
+        .await
+        .expect("Failed to parse json");
+    allocations.sort_by_key(|allocation| allocation["batchref"].as_str().map(str::to_owned));
+    let mut expected = vec![
+        serde_json::json!({ "sku": sku, "qty": 3, "batchref": batchref, "eta": "2011-01-02" }),
+        serde_json::json!({ "sku": othersku, "qty": 4, "batchref": otherbatch, "eta": null }),
+    ];
+    expected.sort_by_key(|allocation| allocation["batchref"].as_str().map(str::to_owned));
+    assert_eq!(allocations, expected);
+}
+
+#[tokio::test]
+async fn api_returns_404_for_unknown_order() {
+    // Arrange
+    let app = spawn_app().await;
+    let orderid = random_orderid("unknown");
+
+    // Act
+    let response = reqwest::Client::new()
+        .get(format!("{}/allocations/{}", &app.address, orderid))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 404);
+    let problem = problem(response).await;
+    assert_eq!(problem["code"], "not-found");
+    assert_eq!(problem["orderid"], orderid);
+}
+
+#[tokio::test]
+async fn api_lists_batches_with_filters_and_cursor_pagination() {
+    // Arrange
+    let sku = random_sku("");
+    let othersku = random_sku("other");
+    let app = spawn_app().await;
+    let batchrefs: Vec<String> = (0..3).map(|i| random_batchref(&i.to_string())).collect();
+    add_stock(
+        &app,
+        &[
+            (batchrefs[0].clone(), sku.clone(), 10, None),
+            (random_batchref("other"), othersku, 10, None),
+            (batchrefs[1].clone(), sku.clone(), 10, Some("2011-01-01")),
+            (batchrefs[2].clone(), sku.clone(), 10, Some("2011-02-01")),
+        ],
+    )
+    .await;
+    let response = post_allocate(&app, &sku, 10).await;
+    assert_eq!(response.status().as_u16(), 201);
+    let client = reqwest::Client::new();
+    let list = |query: String| {
+        let client = &client;
+        let address = &app.address;
+        async move {
+            let response = client
+                .get(format!("{}/batches?{}", address, query))
+                .send()
+                .await
+                .expect("Failed to execute request");
+            assert_eq!(response.status().as_u16(), 200);
+            response
+                .json::<serde_json::Value>()
+                .await
+                .expect("Failed to parse json")
+        }
+    };
+    let references = |page: &serde_json::Value| -> Vec<String> {
+        page["batches"]
+            .as_array()
+            .expect("batches")
+            .iter()
+            .map(|batch| batch["reference"].as_str().expect("reference").to_owned())
+            .collect()
+    };
+
+    // Act
+    let first = list(format!("sku={}&limit=2", sku)).await;
+    let cursor = first["next_cursor"].as_str().expect("next cursor");
+    let second = list(format!("sku={}&limit=2&cursor={}", sku, cursor)).await;
+    let available = list(format!("sku={}&has_availability=true", sku)).await;
+    let in_january = list(format!("sku={}&eta_from=2011-01-01&eta_to=2011-01-31", sku)).await;
+
+    // Assert
+    assert_eq!(references(&first), batchrefs[..2]);
+    assert_eq!(first["batches"][0]["allocated_quantity"], 10);
+    assert_eq!(first["batches"][0]["available_quantity"], 0);
+    assert_eq!(references(&second), batchrefs[2..]);
+    assert_eq!(second["next_cursor"], serde_json::Value::Null);
+    assert_eq!(references(&available), batchrefs[1..]);
+    assert_eq!(references(&in_january), batchrefs[1..2]);
+}
+
+#[tokio::test]
+async fn api_returns_validation_problem_for_bad_cursor() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .get(format!("{}/batches?cursor=not-a-cursor", &app.address))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+    assert_eq!(problem(response).await["field"], "cursor");
+}
+
+#[tokio::test]
+async fn concurrent_allocations_never_oversell() {
+    // Arrange
+    let sku = random_sku("");
+    let batchref = random_batchref("1");
+    let app = spawn_app().await;
+    add_stock(&app, &[(batchref.clone(), sku.clone(), 10, None)]).await;
+    let client = reqwest::Client::new();
+
+    // Act
+    let requests = (0..25).map(|i| {
+        let data = serde_json::json!({
+            "orderid": random_orderid(&i.to_string()),
+            "sku": sku.clone(),
+            "qty": 1,
+        });
+        client
+            .post(format!("{}/allocate", &app.address))
+            .json(&data)
+            .send()
+    });
+    let responses = futures_util::future::join_all(requests).await;
+    let mut statuses = HashMap::new();
+    let mut out_of_stock = 0;
+    for response in responses {
+        let response = response.expect("Failed to execute request");
+        let status = response.status().as_u16();
+        *statuses.entry(status).or_insert(0) += 1;
+        if status == 400 {
+            let problem = response.json::<serde_json::Value>().await.unwrap();
+            if problem["code"] == "out-of-stock" {
+                out_of_stock += 1;
+            }
+        }
+    }
+
+    // Assert
+    let row = sqlx::query(
+        "SELECT batches._purchased_quantity - COALESCE(SUM(order_lines.qty), 0) AS available
+        FROM batches
+        LEFT JOIN allocations ON allocations.batch_id = batches.id
+        LEFT JOIN order_lines ON order_lines.id = allocations.orderline_id
+        WHERE batches.reference = $1
+        GROUP BY batches.id",
+    )
+    .bind(&batchref)
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("select available quantity");
+    let available: i64 = row.try_get("available").expect("get available");
+    assert_eq!(available, 0);
+    assert_eq!(statuses.get(&201), Some(&10), "{:?}", statuses);
+    assert_eq!(statuses.get(&400), Some(&15), "{:?}", statuses);
+    assert_eq!(out_of_stock, 15);
+}
+
+#[tokio::test]
+async fn api_allocates_and_reads_allocations_on_a_single_connection() {
+    // Arrange
+    let sku = random_sku("");
+    let batchref = random_batchref("");
+    let orderid = random_orderid("");
+    let app = spawn_app_with(
+        Arc::new(ManualClock::new(today())),
+        SqlitePoolOptions::new().max_connections(1),
+    )
+    .await;
+    add_stock(&app, &[(batchref.clone(), sku.clone(), 10, None)]).await;
+    let client = reqwest::Client::new();
+
+    // Act
+    let response = client
+        .post(format!("{}/allocate", &app.address))
+        .json(&serde_json::json!({ "orderid": orderid, "sku": sku, "qty": 3 }))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 201);
+    let allocations = client
+        .get(format!("{}/allocations/{}", &app.address, orderid))
+        .send()
+        .await
+        .expect("Failed to execute request")
+        .json::<serde_json::Value>()
+        .await
+        .expect("Failed to parse allocations");
+    assert_eq!(allocations[0]["batchref"], batchref);
+}
+
+#[tokio::test]
+async fn change_batch_quantity_reallocates_overflowing_lines() {
+    // Arrange
+    let sku = random_sku("");
+    let earlybatch = random_batchref("1");
+    let laterbatch = random_batchref("2");
+    let app = spawn_app().await;
+    add_stock(
+        &app,
+        &[
+            (earlybatch.clone(), sku.clone(), 10, Some("2011-01-01")),
+            (laterbatch.clone(), sku.clone(), 10, Some("2011-01-02")),
+        ],
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let orderid = random_orderid("");
+    let response = client
+        .post(format!("{}/allocate", &app.address))
+        .json(&serde_json::json!({ "orderid": orderid, "sku": sku, "qty": 8 }))
+        .send()
+        .await
+        .expect("Failed to execute request");
+    assert_eq!(response.status().as_u16(), 201);
+
+    // Act
+    let response = client
+        .post(format!("{}/change_batch_quantity", &app.address))
+        .json(&serde_json::json!({ "batchref": earlybatch, "qty": 5 }))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let row = sqlx::query(
+        "SELECT batches.reference
+        FROM allocations
+        JOIN batches ON batches.id = allocations.batch_id
+        JOIN order_lines ON order_lines.id = allocations.orderline_id
+        WHERE order_lines.orderid = $1",
+    )
+    .bind(&orderid)
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("select allocation");
+    let batchref: String = row.try_get("reference").expect("get reference");
+    assert_eq!(batchref, laterbatch);
+}
+
+#[tokio::test]
+async fn api_returns_400_when_out_of_stock() {
+    // Arrange
+    let sku = random_sku("");
+    let app = spawn_app().await;
+    add_stock(&app, &[(random_batchref("1"), sku.clone(), 10, None)]).await;
+
+    // Act
+    let response = post_allocate(&app, &sku, 20).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+    let problem = problem(response).await;
+    assert_eq!(problem["type"], "/problems/out-of-stock");
+    assert_eq!(problem["code"], "out-of-stock");
+    assert_eq!(problem["status"], 400);
+    assert_eq!(problem["sku"], sku);
+}
+
+#[tokio::test]
+async fn api_returns_400_for_invalid_sku() {
+    // Arrange
+    let unknown_sku = random_sku("unknown");
+    let app = spawn_app().await;
+
+    // Act
+    let response = post_allocate(&app, &unknown_sku, 20).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+    let problem = problem(response).await;
+    assert_eq!(problem["code"], "invalid-sku");
+    assert_eq!(problem["sku"], unknown_sku);
+    assert_eq!(problem["detail"], format!("Invalid sku '{}'", unknown_sku));
+}
+
+#[tokio::test]
+async fn api_returns_409_when_the_product_keeps_being_modified() {
+    // Arrange
+    let sku = random_sku("");
+    let app = spawn_app().await;
+    add_stock(&app, &[(random_batchref("1"), sku.clone(), 10, None)]).await;
+    // Silently drop every version bump, as if another writer always won.
+    sqlx::query(
+        "CREATE TRIGGER lose_every_race BEFORE INSERT ON products
+        BEGIN SELECT RAISE(IGNORE); END",
+    )
+    .execute(&app.db_pool)
+    .await
+    .expect("create trigger");
+
+    // Act
+    let response = post_allocate(&app, &sku, 1).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 409);
+    let problem = problem(response).await;
+    assert_eq!(problem["code"], "conflict");
+    assert_eq!(problem["sku"], sku);
+}
+
+#[tokio::test]
+async fn api_returns_503_when_storage_fails() {
+    // Arrange
+    let sku = random_sku("");
+    let app = spawn_app().await;
+    add_stock(&app, &[(random_batchref("1"), sku.clone(), 10, None)]).await;
+    sqlx::query("DROP TABLE allocations")
+        .execute(&app.db_pool)
+        .await
+        .expect("drop allocations");
+
+    // Act
+    let response = post_allocate(&app, &sku, 1).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 503);
+    assert_eq!(problem(response).await["code"], "unavailable");
+}
+
+#[tokio::test]
+async fn api_returns_validation_problem_for_missing_field() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/allocate", &app.address))
+        .json(&serde_json::json!({ "orderid": random_orderid(""), "sku": random_sku("") }))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+    let problem = problem(response).await;
+    assert_eq!(problem["code"], "validation-failed");
+    assert_eq!(problem["field"], "qty");
+}
+
+#[tokio::test]
+async fn api_returns_validation_problem_for_zero_quantity() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = post_allocate(&app, &random_sku(""), 0).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+    let problem = problem(response).await;
+    assert_eq!(problem["code"], "validation-failed");
+    assert_eq!(problem["field"], "qty");
+}
+
+#[tokio::test]
+async fn api_returns_validation_problem_for_zero_quantity_deallocation() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/deallocate", &app.address))
+        .json(&serde_json::json!({
+            "orderid": random_orderid(""),
+            "sku": random_sku(""),
+            "qty": 0,
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+    let problem = problem(response).await;
+    assert_eq!(problem["code"], "validation-failed");
+    assert_eq!(problem["field"], "qty");
+}
+
+#[tokio::test]
+async fn api_returns_validation_problem_for_zero_batch_quantity() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/change_batch_quantity", &app.address))
+        .json(&serde_json::json!({ "batchref": random_batchref(""), "qty": 0 }))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+    let problem = problem(response).await;
+    assert_eq!(problem["code"], "validation-failed");
+    assert_eq!(problem["field"], "qty");
+}
+
+#[tokio::test]
+async fn api_splits_a_line_across_batches_when_asked_to() {
+    // Arrange
+    let sku = random_sku("");
+    let earlybatch = random_batchref("1");
+    let laterbatch = random_batchref("2");
+    let orderid = random_orderid("");
+    let app = spawn_app().await;
+    add_stock(
+        &app,
+        &[
+            (laterbatch.clone(), sku.clone(), 10, Some("2011-01-02")),
+            (earlybatch.clone(), sku.clone(), 10, Some("2011-01-01")),
+        ],
+    )
+    .await;
+    let client = reqwest::Client::new();
+
+    // Act
+    let response = client
+        .post(format!("{}/allocate", &app.address))
+        .json(&serde_json::json!({
+            "orderid": orderid,
+            "sku": sku,
+            "qty": 15,
+            "split": true,
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 201);
+    let body = response
+        .json::<serde_json::Value>()
+        .await
+        .expect("Failed to parse response");
+    assert_eq!(
+        body["allocations"],
+        serde_json::json!([
+            { "batchref": earlybatch, "qty": 10 },
+            { "batchref": laterbatch, "qty": 5 },
+        ])
+    );
+    let allocations = client
+        .get(format!("{}/allocations/{}", &app.address, orderid))
+        .send()
+        .await
+        .expect("Failed to execute request")
+        .json::<Vec<serde_json::Value>>()
+        .await
+        .expect("Failed to parse allocations");
+    assert_eq!(allocations.len(), 2);
+}
+
+#[tokio::test]
+async fn api_deallocates_a_split_line_from_every_batch() {
+    // Arrange
+    let sku = random_sku("");
+    let earlybatch = random_batchref("1");
+    let laterbatch = random_batchref("2");
+    let orderid = random_orderid("");
+    let app = spawn_app().await;
+    add_stock(
+        &app,
+        &[
+            (earlybatch.clone(), sku.clone(), 10, Some("2011-01-01")),
+            (laterbatch.clone(), sku.clone(), 10, Some("2011-01-02")),
+        ],
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let line = serde_json::json!({ "orderid": orderid, "sku": sku, "qty": 15 });
+    let response = client
+        .post(format!("{}/allocate", &app.address))
+        .json(&serde_json::json!({ "orderid": orderid, "sku": sku, "qty": 15, "split": true }))
+        .send()
+        .await
+        .expect("Failed to execute request");
+    assert_eq!(response.status().as_u16(), 201);
+
+    // Act
+    let response = client
+        .post(format!("{}/deallocate", &app.address))
+        .json(&line)
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let response = client
+        .get(format!("{}/allocations/{}", &app.address, orderid))
+        .send()
+        .await
+        .expect("Failed to execute request");
+    assert_eq!(response.status().as_u16(), 404);
+    let response = post_allocate(&app, &sku, 20).await;
+    assert_eq!(response.status().as_u16(), 400);
+    let response = client
+        .post(format!("{}/allocate", &app.address))
+        .json(&serde_json::json!({ "orderid": random_orderid(""), "sku": sku, "qty": 20, "split": true }))
+        .send()
+        .await
+        .expect("Failed to execute request");
+    assert_eq!(response.status().as_u16(), 201);
+}
+
+#[tokio::test]
+async fn api_does_not_split_a_line_unless_asked_to() {
+    // Arrange
+    let sku = random_sku("");
+    let app = spawn_app().await;
+    add_stock(
+        &app,
+        &[
+            (random_batchref("1"), sku.clone(), 10, None),
+            (random_batchref("2"), sku.clone(), 10, None),
+        ],
+    )
+    .await;
+
+    // Act
+    let response = post_allocate(&app, &sku, 15).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+    assert_eq!(problem(response).await["code"], "out-of-stock");
+}
+
+#[tokio::test]
+async fn api_lists_shipments_as_in_stock_once_their_eta_has_passed() {
+    // Arrange
+    let sku = random_sku("");
+    let clock = Arc::new(ManualClock::new(today()));
+    let app = spawn_app_with_clock(clock.clone()).await;
+    add_stock(
+        &app,
+        &[(random_batchref(""), sku.clone(), 10, Some("2011-01-03"))],
+    )
+    .await;
+    let in_stock = || async {
+        reqwest::Client::new()
+            .get(format!("{}/batches", &app.address))
+            .query(&[("sku", sku.as_str()), ("in_stock", "true")])
+            .send()
+            .await
+            .expect("Failed to execute request")
+            .json::<serde_json::Value>()
+            .await
+            .expect("Failed to parse batches")["batches"]
+            .as_array()
+            .map_or(0, Vec::len)
+    };
+
+    // Act & Assert
+    assert_eq!(in_stock().await, 0);
+    clock.advance(chrono::Duration::days(2));
+    assert_eq!(in_stock().await, 1);
+}
+
+#[tokio::test]
+async fn api_returns_not_found_problem_for_unknown_batch() {
+    // Arrange
+    let app = spawn_app().await;
+    let batchref = random_batchref("unknown");
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/change_batch_quantity", &app.address))
+        .json(&serde_json::json!({ "batchref": batchref, "qty": 5 }))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 404);
+    let problem = problem(response).await;
+    assert_eq!(problem["code"], "not-found");
+    assert_eq!(problem["reference"], batchref);
+}
+
+/// Checks the problem+json content type and parses the body.
+async fn problem(response: reqwest::Response) -> serde_json::Value {
+    assert_eq!(
+        response.headers()[reqwest::header::CONTENT_TYPE],
+        "application/problem+json"
+    );
+    response
+        .json::<serde_json::Value>()
+        .await
+        .expect("Failed to parse problem")
+}
+
+async fn post_allocate(app: &TestApp, sku: &str, qty: u32) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(format!("{}/allocate", &app.address))
+        .json(&serde_json::json!({
+            "orderid": random_orderid(""),
+            "sku": sku,
+            "qty": qty,
+        }))
+        .send()
+        .await
+        .expect("Failed to execute request")
+}
+
 pub struct TestApp {
     pub address: String,
     pub db_pool: SqlitePool,
 }
 
+/// The date test apps are frozen at, before every ETA the tests use.
+fn today() -> chrono::NaiveDate {
+    chrono::NaiveDate::from_ymd_opt(2011, 1, 1).unwrap()
+}
+
 async fn spawn_app() -> TestApp {
+    spawn_app_with_clock(Arc::new(ManualClock::new(today()))).await
+}
+
+async fn spawn_app_with_clock(clock: Arc<dyn Clock>) -> TestApp {
+    spawn_app_with(clock, SqlitePoolOptions::new()).await
+}
+
+async fn spawn_app_with(clock: Arc<dyn Clock>, pool_options: SqlitePoolOptions) -> TestApp {
     let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind random port");
     let port = listener.local_addr().unwrap().port();
     let address = format!("http://127.0.0.1:{}", port);
 
-    let db_pool = SqlitePool::connect("sqlite::memory:")
+    let options = infrastructure::connect_options("sqlite::memory:").expect("Invalid database url");
+    let db_pool = pool_options
+        .connect_with(options)
         .await
         .expect("Failed to connect to db");
 
@@ -124,7 +1026,9 @@ async fn spawn_app() -> TestApp {
         .await
         .expect("failed to run migrations");
     let db_pool_clone = db_pool.clone();
-    tokio::spawn(async move { startup::run(listener, db_pool_clone).await });
+    tokio::spawn(
+        async move { startup::run(listener, db_pool_clone, Default::default(), clock).await },
+    );
 
     TestApp { address, db_pool }
 }
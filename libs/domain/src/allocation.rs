@@ -0,0 +1,405 @@
+//! Strategies deciding which batches of a product an order line is
+//! allocated from first.
+use std::{cmp::Ordering, collections::HashMap, fmt, sync::Arc};
+
+use crate::model::{sort_by_eta, Batch, OrderLine};
+
+/// Orders the batches of a product by preference for allocating `line` on
+/// `today`.
+///
+/// [`Product`](crate::model::Product) sorts its batches with
+/// [`compare`](Self::compare) and allocates from the first batch that can
+/// take the line, or, when splitting, from as many as it takes in that order.
+/// Batches the strategy does not [`accept`](Self::accepts) are skipped.
+pub trait AllocationStrategy: fmt::Debug + Send + Sync {
+    /// The name the strategy is configured by, one of [`BUILTIN_NAMES`] for
+    /// the built-in ones.
+    fn name(&self) -> &'static str;
+
+    /// Whether `line` may be allocated from `batch` at all on `today`.
+    fn accepts(&self, _batch: &Batch, _today: &chrono::NaiveDate) -> bool {
+        true
+    }
+
+    fn compare(
+        &self,
+        a: &Batch,
+        b: &Batch,
+        line: &OrderLine,
+        today: &chrono::NaiveDate,
+    ) -> Ordering;
+}
+
+/// In-stock batches first, then the earliest shipments; see
+/// [`sort_by_eta`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EarliestEta;
+
+impl AllocationStrategy for EarliestEta {
+    fn name(&self) -> &'static str {
+        "earliest-eta"
+    }
+
+    fn compare(
+        &self,
+        a: &Batch,
+        b: &Batch,
+        _line: &OrderLine,
+        today: &chrono::NaiveDate,
+    ) -> Ordering {
+        sort_by_eta(a, b, today)
+    }
+}
+
+/// First expired, first out: batches expiring soonest first, batches that
+/// never expire last, by ETA otherwise. Stock that expired before today is
+/// never allocated.
+///
+/// Batches in stock are always preferred to shipments, which are ordered
+/// the same way after them.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Fefo;
+
+impl AllocationStrategy for Fefo {
+    fn name(&self) -> &'static str {
+        "fefo"
+    }
+
+    fn accepts(&self, batch: &Batch, today: &chrono::NaiveDate) -> bool {
+        batch.expiry().is_none_or(|expiry| expiry >= *today)
+    }
+
+    fn compare(
+        &self,
+        a: &Batch,
+        b: &Batch,
+        _line: &OrderLine,
+        today: &chrono::NaiveDate,
+    ) -> Ordering {
+        let by_expiry = match (a.expiry(), b.expiry()) {
+            (Some(a_expiry), Some(b_expiry)) => a_expiry.cmp(&b_expiry),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        };
+        in_stock_first(a, b, today)
+            .then(by_expiry)
+            .then_with(|| sort_by_eta(a, b, today))
+    }
+}
+
+/// Keeps stock in as few batches as possible: batches that can take the
+/// whole line come first, the one left with the least stock leading; the
+/// others follow fullest first, so that a split line takes few fragments.
+///
+/// Batches in stock are always preferred to shipments, which are ordered
+/// the same way after them.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BestFit;
+
+impl AllocationStrategy for BestFit {
+    fn name(&self) -> &'static str {
+        "best-fit"
+    }
+
+    fn compare(
+        &self,
+        a: &Batch,
+        b: &Batch,
+        line: &OrderLine,
+        today: &chrono::NaiveDate,
+    ) -> Ordering {
+        let (a_available, b_available) = (a.available_quantity(), b.available_quantity());
+        let by_fit = match (a_available >= line.qty(), b_available >= line.qty()) {
+            (true, true) => a_available.cmp(&b_available),
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+            (false, false) => b_available.cmp(&a_available),
+        };
+        in_stock_first(a, b, today)
+            .then(by_fit)
+            .then_with(|| sort_by_eta(a, b, today))
+    }
+}
+
+/// Batches with the most available stock first, by ETA otherwise; batches
+/// in stock are always preferred to shipments.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LargestRemaining;
+
+impl AllocationStrategy for LargestRemaining {
+    fn name(&self) -> &'static str {
+        "largest-remaining"
+    }
+
+    fn compare(
+        &self,
+        a: &Batch,
+        b: &Batch,
+        _line: &OrderLine,
+        today: &chrono::NaiveDate,
+    ) -> Ordering {
+        in_stock_first(a, b, today)
+            .then_with(|| b.available_quantity().cmp(&a.available_quantity()))
+            .then_with(|| sort_by_eta(a, b, today))
+    }
+}
+
+/// Batches in stock on `today` before shipments, whatever their ETAs.
+fn in_stock_first(a: &Batch, b: &Batch, today: &chrono::NaiveDate) -> Ordering {
+    b.is_in_stock(today).cmp(&a.is_in_stock(today))
+}
+
+/// Names of the built-in strategies, as accepted by [`builtin`].
+pub const BUILTIN_NAMES: [&str; 4] = ["earliest-eta", "fefo", "best-fit", "largest-remaining"];
+
+/// The built-in strategy called `name`, if any.
+pub fn builtin(name: &str) -> Option<Arc<dyn AllocationStrategy>> {
+    match name {
+        "earliest-eta" => Some(Arc::new(EarliestEta)),
+        "fefo" => Some(Arc::new(Fefo)),
+        "best-fit" => Some(Arc::new(BestFit)),
+        "largest-remaining" => Some(Arc::new(LargestRemaining)),
+        _ => None,
+    }
+}
+
+/// The strategy to allocate each SKU with: a default, overridden per SKU.
+#[derive(Debug, Clone)]
+pub struct AllocationStrategies {
+    default: Arc<dyn AllocationStrategy>,
+    by_sku: HashMap<String, Arc<dyn AllocationStrategy>>,
+}
+
+impl Default for AllocationStrategies {
+    fn default() -> Self {
+        Self::new(Arc::new(EarliestEta))
+    }
+}
+
+impl AllocationStrategies {
+    pub fn new(default: Arc<dyn AllocationStrategy>) -> Self {
+        Self {
+            default,
+            by_sku: HashMap::new(),
+        }
+    }
+
+    pub fn with_sku(
+        mut self,
+        sku: impl Into<String>,
+        strategy: Arc<dyn AllocationStrategy>,
+    ) -> Self {
+        self.by_sku.insert(sku.into(), strategy);
+        self
+    }
+
+    pub fn for_sku(&self, sku: &str) -> Arc<dyn AllocationStrategy> {
+        self.by_sku.get(sku).unwrap_or(&self.default).clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::ManualClock, model::Product};
+
+    fn today() -> Option<chrono::NaiveDate> {
+        Some(chrono::NaiveDate::from_ymd_opt(2011, 1, 1).unwrap())
+    }
+
+    fn in_days(days: i64) -> Option<chrono::NaiveDate> {
+        today().map(|today| today + chrono::Duration::days(days))
+    }
+
+    fn batch(reference: &str, qty: u32, eta: Option<chrono::NaiveDate>) -> Batch {
+        Batch::new(reference.to_owned(), "SHINY-SPOON".to_owned(), qty, eta)
+    }
+
+    fn line(qty: u32) -> OrderLine {
+        OrderLine::new("order1".to_owned(), "SHINY-SPOON".to_owned(), qty)
+    }
+
+    fn order(strategy: &dyn AllocationStrategy, mut batches: Vec<Batch>, qty: u32) -> Vec<String> {
+        let line = line(qty);
+        let today = today().unwrap();
+        batches.sort_by(|a, b| strategy.compare(a, b, &line, &today));
+        batches
+            .iter()
+            .map(|batch| batch.reference().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn earliest_eta_prefers_stock_then_earliest_shipments() {
+        let batches = vec![
+            batch("slow", 100, in_days(7)),
+            batch("in-stock", 100, None),
+            batch("fast", 100, in_days(1)),
+        ];
+
+        assert_eq!(
+            order(&EarliestEta, batches, 10),
+            vec!["in-stock", "fast", "slow"]
+        );
+    }
+
+    #[test]
+    fn fefo_prefers_the_soonest_expiring_stock() {
+        let batches = vec![
+            batch("no-expiry", 100, None),
+            batch("late-expiry", 100, None).with_expiry(in_days(30)),
+            batch("early-expiry", 100, today()).with_expiry(in_days(10)),
+        ];
+
+        assert_eq!(
+            order(&Fefo, batches, 10),
+            vec!["early-expiry", "late-expiry", "no-expiry"]
+        );
+    }
+
+    #[test]
+    fn fefo_prefers_stock_to_shipments_expiring_sooner() {
+        let batches = vec![
+            batch("shipment", 100, in_days(1)).with_expiry(in_days(5)),
+            batch("stock", 100, None).with_expiry(in_days(30)),
+        ];
+
+        assert_eq!(order(&Fefo, batches, 10), vec!["stock", "shipment"]);
+    }
+
+    #[test]
+    fn fefo_skips_stock_that_has_expired() {
+        let expired = batch("expired", 100, None).with_expiry(in_days(-1));
+        let expiring = batch("expiring", 100, None).with_expiry(today());
+        let today = today().unwrap();
+
+        assert!(!Fefo.accepts(&expired, &today));
+        assert!(Fefo.accepts(&expiring, &today));
+        assert!(Fefo.accepts(&batch("no-expiry", 100, None), &today));
+        assert!(EarliestEta.accepts(&expired, &today));
+    }
+
+    #[test]
+    fn best_fit_prefers_the_smallest_batch_that_takes_the_whole_line() {
+        let batches = vec![
+            batch("large", 100, today()),
+            batch("too-small", 5, today()),
+            batch("snug", 12, None),
+        ];
+
+        assert_eq!(
+            order(&BestFit, batches, 10),
+            vec!["snug", "large", "too-small"]
+        );
+    }
+
+    #[test]
+    fn best_fit_takes_the_fullest_batches_first_when_none_fits() {
+        let batches = vec![
+            batch("small", 4, today()),
+            batch("medium", 8, today()),
+            batch("tiny", 2, today()),
+        ];
+
+        assert_eq!(
+            order(&BestFit, batches, 20),
+            vec!["medium", "small", "tiny"]
+        );
+    }
+
+    #[test]
+    fn largest_remaining_prefers_the_most_available_stock() {
+        let mut partly_allocated = batch("partly-allocated", 100, None);
+        partly_allocated.allocate(OrderLine::new(
+            "order0".to_owned(),
+            "SHINY-SPOON".to_owned(),
+            60,
+        ));
+        let batches = vec![
+            partly_allocated,
+            batch("medium", 50, today()),
+            batch("large", 80, None),
+        ];
+
+        assert_eq!(
+            order(&LargestRemaining, batches, 10),
+            vec!["large", "medium", "partly-allocated"]
+        );
+    }
+
+    #[test]
+    fn size_based_strategies_prefer_stock_to_shipments() {
+        let batches = || {
+            vec![
+                batch("shipment", 100, in_days(1)),
+                batch("small-stock", 10, None),
+                batch("large-stock", 20, None),
+            ]
+        };
+
+        assert_eq!(
+            order(&LargestRemaining, batches(), 5),
+            vec!["large-stock", "small-stock", "shipment"]
+        );
+        assert_eq!(
+            order(&BestFit, batches(), 5),
+            vec!["small-stock", "large-stock", "shipment"]
+        );
+    }
+
+    #[test]
+    fn products_allocate_with_the_strategy_they_are_given() {
+        let mut product = Product::new(
+            "SHINY-SPOON".to_owned(),
+            vec![batch("small", 10, None), batch("large", 100, today())],
+        );
+        product.set_strategy(Arc::new(LargestRemaining));
+        product.set_clock(Arc::new(ManualClock::new(today().unwrap())));
+
+        assert_eq!(product.allocate(line(5)), Ok("large".to_owned()));
+    }
+
+    #[test]
+    fn products_do_not_allocate_from_batches_the_strategy_rejects() {
+        let mut product = Product::new(
+            "SHINY-SPOON".to_owned(),
+            vec![
+                batch("expired", 100, None).with_expiry(in_days(-1)),
+                batch("fresh", 10, None).with_expiry(in_days(5)),
+            ],
+        );
+        product.set_strategy(Arc::new(Fefo));
+        product.set_clock(Arc::new(ManualClock::new(today().unwrap())));
+
+        assert_eq!(product.allocate(line(5)), Ok("fresh".to_owned()));
+        assert_eq!(
+            product.allocate_split(OrderLine::new(
+                "order2".to_owned(),
+                "SHINY-SPOON".to_owned(),
+                10,
+            )),
+            Err(crate::Error::OutOfStock("SHINY-SPOON".to_owned()))
+        );
+    }
+
+    #[test]
+    fn strategies_are_selected_per_sku() {
+        let strategies =
+            AllocationStrategies::default().with_sku("SHINY-SPOON", Arc::new(LargestRemaining));
+
+        assert_eq!(
+            strategies.for_sku("SHINY-SPOON").name(),
+            "largest-remaining"
+        );
+        assert_eq!(strategies.for_sku("DULL-SPOON").name(), "earliest-eta");
+    }
+
+    #[test]
+    fn every_builtin_name_is_known() {
+        for name in BUILTIN_NAMES {
+            assert_eq!(builtin(name).map(|strategy| strategy.name()), Some(name));
+        }
+        assert!(builtin("random").is_none());
+    }
+}
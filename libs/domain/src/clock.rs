@@ -0,0 +1,87 @@
+//! Source of the current date, injected wherever a decision depends on it so
+//! that it can be frozen or advanced in tests.
+use std::{fmt, sync::Mutex};
+
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn today(&self) -> chrono::NaiveDate;
+}
+
+/// The date of the system clock in the timezone of the warehouse, UTC
+/// unless told otherwise.
+#[derive(Debug, Clone, Copy)]
+pub struct SystemClock {
+    offset: chrono::FixedOffset,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::with_offset(chrono::FixedOffset::east_opt(0).expect("UTC is a valid offset"))
+    }
+}
+
+impl SystemClock {
+    pub fn with_offset(offset: chrono::FixedOffset) -> Self {
+        Self { offset }
+    }
+}
+
+impl Clock for SystemClock {
+    fn today(&self) -> chrono::NaiveDate {
+        chrono::Utc::now().with_timezone(&self.offset).date_naive()
+    }
+}
+
+/// A clock that stands still until it is set or advanced.
+#[derive(Debug)]
+pub struct ManualClock {
+    today: Mutex<chrono::NaiveDate>,
+}
+
+impl ManualClock {
+    pub fn new(today: chrono::NaiveDate) -> Self {
+        Self {
+            today: Mutex::new(today),
+        }
+    }
+
+    pub fn set(&self, today: chrono::NaiveDate) {
+        *self.today.lock().unwrap() = today;
+    }
+
+    pub fn advance(&self, duration: chrono::Duration) {
+        let mut today = self.today.lock().unwrap();
+        *today += duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn today(&self) -> chrono::NaiveDate {
+        *self.today.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clock_stands_still_until_moved() {
+        let clock = ManualClock::new(chrono::NaiveDate::from_ymd_opt(2011, 1, 1).unwrap());
+        assert_eq!(
+            clock.today(),
+            chrono::NaiveDate::from_ymd_opt(2011, 1, 1).unwrap()
+        );
+
+        clock.advance(chrono::Duration::days(2));
+        assert_eq!(
+            clock.today(),
+            chrono::NaiveDate::from_ymd_opt(2011, 1, 3).unwrap()
+        );
+
+        clock.set(chrono::NaiveDate::from_ymd_opt(2011, 2, 1).unwrap());
+        assert_eq!(
+            clock.today(),
+            chrono::NaiveDate::from_ymd_opt(2011, 2, 1).unwrap()
+        );
+    }
+}
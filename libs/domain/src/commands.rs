@@ -0,0 +1,32 @@
+/// Requests for the system to do something, each handled by exactly one
+/// handler in the service layer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Allocate {
+        orderid: String,
+        sku: String,
+        qty: u32,
+    },
+    /// Like `Allocate`, but may split the line across several batches.
+    AllocateSplit {
+        orderid: String,
+        sku: String,
+        qty: u32,
+    },
+    CreateBatch {
+        reference: String,
+        sku: String,
+        qty: u32,
+        eta: Option<chrono::NaiveDate>,
+        expiry: Option<chrono::NaiveDate>,
+    },
+    ChangeBatchQuantity {
+        reference: String,
+        qty: u32,
+    },
+    Deallocate {
+        orderid: String,
+        sku: String,
+        qty: u32,
+    },
+}
@@ -0,0 +1,35 @@
+/// Things that happened to an aggregate, recorded for the service layer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Allocated {
+        orderid: String,
+        sku: String,
+        qty: u32,
+        batchref: String,
+    },
+    Deallocated {
+        orderid: String,
+        sku: String,
+        qty: u32,
+        batchref: String,
+    },
+    OutOfStock {
+        sku: String,
+    },
+    BatchQuantityChanged {
+        batchref: String,
+        qty: u32,
+    },
+}
+
+impl Event {
+    /// Name used to look up the handlers registered for this kind of event.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Allocated { .. } => "Allocated",
+            Self::Deallocated { .. } => "Deallocated",
+            Self::OutOfStock { .. } => "OutOfStock",
+            Self::BatchQuantityChanged { .. } => "BatchQuantityChanged",
+        }
+    }
+}
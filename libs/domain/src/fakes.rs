@@ -0,0 +1,391 @@
+//! In-memory implementations of the domain ports, for fast tests.
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+
+use crate::{
+    events::Event,
+    model,
+    repository::{Repository, RepositoryError},
+    unit_of_work::{TrackingRepository, UnitOfWork, UnitOfWorkFactory},
+};
+
+/// Keeps products in memory and can be told to fail a number of upcoming
+/// loads or saves, so that error paths are reproducible.
+#[derive(Default)]
+pub struct FakeRepository {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    products: BTreeMap<String, model::Product>,
+    load_failures: VecDeque<RepositoryError>,
+    save_failures: VecDeque<RepositoryError>,
+}
+
+impl FakeRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_products(products: impl IntoIterator<Item = model::Product>) -> Self {
+        let repo = Self::new();
+        {
+            let mut state = repo.state.lock().unwrap();
+            for product in products {
+                state.products.insert(product.sku().to_owned(), product);
+            }
+        }
+        repo
+    }
+
+    /// Makes the next `times` calls to `get`, `list` or `get_by_sku` fail
+    /// with `error`.
+    pub fn fail_next_loads(&self, times: usize, error: RepositoryError) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .load_failures
+            .extend(std::iter::repeat_n(error, times));
+    }
+
+    /// Makes the next `times` calls to `add` or `save` fail with `error`.
+    pub fn fail_next_saves(&self, times: usize, error: RepositoryError) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .save_failures
+            .extend(std::iter::repeat_n(error, times));
+    }
+}
+
+impl State {
+    fn check_load(&mut self) -> Result<(), RepositoryError> {
+        self.load_failures.pop_front().map_or(Ok(()), Err)
+    }
+
+    fn check_save(&mut self) -> Result<(), RepositoryError> {
+        self.save_failures.pop_front().map_or(Ok(()), Err)
+    }
+
+    fn batches(&self) -> impl Iterator<Item = &model::Batch> {
+        self.products.values().flat_map(|p| p.batches())
+    }
+}
+
+#[async_trait]
+impl Repository for FakeRepository {
+    async fn add(&self, batch: model::Batch) -> Result<(), RepositoryError> {
+        let mut state = self.state.lock().unwrap();
+        state.check_save()?;
+        state
+            .products
+            .entry(batch.sku().to_owned())
+            .or_insert_with(|| model::Product::new(batch.sku().to_owned(), Vec::new()))
+            .add_batch(batch);
+        Ok(())
+    }
+
+    async fn get(&self, reference: &str) -> Result<Option<model::Batch>, RepositoryError> {
+        let mut state = self.state.lock().unwrap();
+        state.check_load()?;
+        let batch = state
+            .batches()
+            .find(|b| b.reference() == reference)
+            .cloned();
+        Ok(batch)
+    }
+
+    async fn list(&self) -> Result<Vec<model::Batch>, RepositoryError> {
+        let mut state = self.state.lock().unwrap();
+        state.check_load()?;
+        let batches = state.batches().cloned().collect();
+        Ok(batches)
+    }
+
+    async fn get_by_sku(&self, sku: &str) -> Result<Option<model::Product>, RepositoryError> {
+        let mut state = self.state.lock().unwrap();
+        state.check_load()?;
+        Ok(state.products.get(sku).cloned())
+    }
+
+    async fn save(&self, product: &model::Product) -> Result<(), RepositoryError> {
+        let mut state = self.state.lock().unwrap();
+        state.check_save()?;
+        let stored_version = state
+            .products
+            .get(product.sku())
+            .map_or(0, |p| p.version_number());
+        if stored_version != product.version_number() {
+            return Err(RepositoryError::Concurrency {
+                sku: product.sku().to_owned(),
+                version_number: product.version_number(),
+            });
+        }
+        state.products.insert(
+            product.sku().to_owned(),
+            model::Product::with_version(
+                product.sku().to_owned(),
+                product.batches().to_vec(),
+                product.version_number() + 1,
+            ),
+        );
+        Ok(())
+    }
+}
+
+/// Begins [`FakeUnitOfWork`]s over a shared [`FakeRepository`].
+pub struct FakeUnitOfWorkFactory {
+    store: Arc<FakeRepository>,
+}
+
+impl FakeUnitOfWorkFactory {
+    pub fn new(store: Arc<FakeRepository>) -> Self {
+        Self { store }
+    }
+}
+
+#[async_trait]
+impl UnitOfWorkFactory for FakeUnitOfWorkFactory {
+    type UnitOfWork = FakeUnitOfWork;
+
+    async fn begin(&self) -> Result<FakeUnitOfWork, RepositoryError> {
+        Ok(FakeUnitOfWork {
+            products: TrackingRepository::new(StagedRepository {
+                store: self.store.clone(),
+                staged: Mutex::default(),
+            }),
+            committed: false,
+            events: Vec::new(),
+        })
+    }
+}
+
+/// Stages every write in memory and replays it onto the shared store on
+/// commit, so that dropping it leaves the store untouched.
+pub struct FakeUnitOfWork {
+    products: TrackingRepository<StagedRepository>,
+    committed: bool,
+    events: Vec<Event>,
+}
+
+impl FakeUnitOfWork {
+    pub fn committed(&self) -> bool {
+        self.committed
+    }
+}
+
+#[async_trait]
+impl UnitOfWork for FakeUnitOfWork {
+    type Products = TrackingRepository<StagedRepository>;
+
+    fn products(&self) -> &Self::Products {
+        &self.products
+    }
+
+    async fn commit(&mut self) -> Result<(), RepositoryError> {
+        let staged = self.products.inner();
+        let writes = std::mem::take(&mut staged.staged.lock().unwrap().writes);
+        for write in writes {
+            match write {
+                Write::Add(batch) => staged.store.add(batch).await?,
+                Write::Save(product) => staged.store.save(&product).await?,
+            }
+        }
+        self.committed = true;
+        self.events.extend(self.products.take_events());
+        Ok(())
+    }
+
+    fn collect_new_events(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.events)
+    }
+}
+
+/// Repository of a [`FakeUnitOfWork`]: reads see the staged writes first.
+pub struct StagedRepository {
+    store: Arc<FakeRepository>,
+    staged: Mutex<Staged>,
+}
+
+#[derive(Default)]
+struct Staged {
+    products: BTreeMap<String, model::Product>,
+    writes: Vec<Write>,
+}
+
+enum Write {
+    Add(model::Batch),
+    Save(model::Product),
+}
+
+impl StagedRepository {
+    fn staged_product(&self, sku: &str) -> Option<model::Product> {
+        self.staged.lock().unwrap().products.get(sku).cloned()
+    }
+}
+
+#[async_trait]
+impl Repository for StagedRepository {
+    async fn add(&self, batch: model::Batch) -> Result<(), RepositoryError> {
+        let mut product = match self.get_by_sku(batch.sku()).await? {
+            Some(product) => product,
+            None => model::Product::new(batch.sku().to_owned(), Vec::new()),
+        };
+        product.add_batch(batch.clone());
+        let mut staged = self.staged.lock().unwrap();
+        staged.products.insert(product.sku().to_owned(), product);
+        staged.writes.push(Write::Add(batch));
+        Ok(())
+    }
+
+    async fn get(&self, reference: &str) -> Result<Option<model::Batch>, RepositoryError> {
+        let staged = self
+            .staged
+            .lock()
+            .unwrap()
+            .products
+            .values()
+            .flat_map(|p| p.batches())
+            .find(|b| b.reference() == reference)
+            .cloned();
+        match staged {
+            Some(batch) => Ok(Some(batch)),
+            None => self.store.get(reference).await,
+        }
+    }
+
+    async fn list(&self) -> Result<Vec<model::Batch>, RepositoryError> {
+        let stored = self.store.list().await?;
+        let staged = self.staged.lock().unwrap();
+        Ok(stored
+            .into_iter()
+            .filter(|b| !staged.products.contains_key(b.sku()))
+            .chain(staged.products.values().flat_map(|p| p.batches()).cloned())
+            .collect())
+    }
+
+    async fn get_by_sku(&self, sku: &str) -> Result<Option<model::Product>, RepositoryError> {
+        match self.staged_product(sku) {
+            Some(product) => Ok(Some(product)),
+            None => self.store.get_by_sku(sku).await,
+        }
+    }
+
+    async fn save(&self, product: &model::Product) -> Result<(), RepositoryError> {
+        let current_version = match self.get_by_sku(product.sku()).await? {
+            Some(current) => current.version_number(),
+            None => 0,
+        };
+        if current_version != product.version_number() {
+            return Err(RepositoryError::Concurrency {
+                sku: product.sku().to_owned(),
+                version_number: product.version_number(),
+            });
+        }
+        let mut staged = self.staged.lock().unwrap();
+        staged.products.insert(
+            product.sku().to_owned(),
+            model::Product::with_version(
+                product.sku().to_owned(),
+                product.batches().to_vec(),
+                product.version_number() + 1,
+            ),
+        );
+        staged.writes.push(Write::Save(product.clone()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn product(sku: &str) -> model::Product {
+        model::Product::new(
+            sku.to_owned(),
+            vec![model::Batch::new(
+                "batch1".to_owned(),
+                sku.to_owned(),
+                100,
+                None,
+            )],
+        )
+    }
+
+    #[tokio::test]
+    async fn saving_bumps_the_version() {
+        let repo = FakeRepository::with_products([product("LAMP")]);
+        let loaded = repo.get_by_sku("LAMP").await.unwrap().unwrap();
+
+        repo.save(&loaded).await.unwrap();
+
+        let reloaded = repo.get_by_sku("LAMP").await.unwrap().unwrap();
+        assert_eq!(reloaded.version_number(), loaded.version_number() + 1);
+    }
+
+    #[tokio::test]
+    async fn saving_a_stale_product_is_a_concurrency_error() {
+        let repo = FakeRepository::with_products([product("LAMP")]);
+        let first = repo.get_by_sku("LAMP").await.unwrap().unwrap();
+        let second = first.clone();
+        repo.save(&first).await.unwrap();
+
+        let res = repo.save(&second).await;
+
+        assert_eq!(
+            res,
+            Err(RepositoryError::Concurrency {
+                sku: "LAMP".to_owned(),
+                version_number: 0,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn injected_failures_are_returned_in_order_then_cleared() {
+        let repo = FakeRepository::with_products([product("LAMP")]);
+        let unavailable = RepositoryError::Unavailable("disk on fire".to_owned());
+        repo.fail_next_loads(2, unavailable.clone());
+
+        assert_eq!(repo.list().await, Err(unavailable.clone()));
+        assert_eq!(repo.get("batch1").await, Err(unavailable));
+        assert!(repo.get("batch1").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn unit_of_work_only_writes_to_the_store_on_commit() {
+        let store = Arc::new(FakeRepository::with_products([product("LAMP")]));
+        let factory = FakeUnitOfWorkFactory::new(store.clone());
+        let line = model::OrderLine::new("order1".to_owned(), "LAMP".to_owned(), 10);
+
+        let uow = factory.begin().await.unwrap();
+        let mut loaded = uow.products().get_by_sku("LAMP").await.unwrap().unwrap();
+        loaded.allocate(line.clone()).unwrap();
+        uow.products().save(&loaded).await.unwrap();
+        drop(uow);
+
+        let stored = store.get_by_sku("LAMP").await.unwrap().unwrap();
+        assert_eq!(stored.version_number(), 0);
+
+        let mut uow = factory.begin().await.unwrap();
+        uow.products().save(&loaded).await.unwrap();
+        uow.commit().await.unwrap();
+
+        let stored = store.get_by_sku("LAMP").await.unwrap().unwrap();
+        assert_eq!(stored.version_number(), 1);
+        assert_eq!(stored.batches()[0].available_quantity(), 90);
+        assert!(uow.committed());
+        assert_eq!(
+            uow.collect_new_events(),
+            vec![Event::Allocated {
+                orderid: "order1".to_owned(),
+                sku: "LAMP".to_owned(),
+                qty: 10,
+                batchref: "batch1".to_owned(),
+            }]
+        );
+    }
+}
@@ -1,6 +1,13 @@
+pub mod allocation;
+pub mod clock;
+pub mod commands;
 mod error;
+pub mod events;
+#[cfg(any(test, feature = "fakes"))]
+pub mod fakes;
 pub mod model;
 pub mod repository;
+pub mod unit_of_work;
 
 pub use error::Error;
 
@@ -1,27 +1,33 @@
-use crate::Error;
-use std::{cmp::Ordering, collections};
-
+use crate::{
+    allocation::{AllocationStrategy, EarliestEta},
+    clock::{Clock, SystemClock},
+    events::Event,
+    Error,
+};
+use std::{cmp::Ordering, collections, sync::Arc};
+
+/// Stock of a SKU, in the warehouse or expected to arrive on its ETA.
+///
+/// ETAs and expiry dates are calendar dates in the timezone of the
+/// warehouse, the same as the date of the [`Clock`] they are compared with.
 #[derive(Debug, Clone)]
 pub struct Batch {
     reference: String,
     sku: String,
-    eta: Option<chrono::Date<chrono::Utc>>,
+    eta: Option<chrono::NaiveDate>,
+    expiry: Option<chrono::NaiveDate>,
     purchased_quantity: u32,
     allocations: collections::HashSet<OrderLine>,
 }
 
 impl Batch {
-    pub fn new(
-        reference: String,
-        sku: String,
-        qty: u32,
-        eta: Option<chrono::Date<chrono::Utc>>,
-    ) -> Self {
+    pub fn new(reference: String, sku: String, qty: u32, eta: Option<chrono::NaiveDate>) -> Self {
         let allocations = collections::HashSet::new();
         Self {
             reference,
             sku,
             eta,
+            expiry: None,
             purchased_quantity: qty,
             allocations,
         }
@@ -31,27 +37,39 @@ impl Batch {
         reference: String,
         sku: String,
         qty: u32,
-        eta: Option<chrono::Date<chrono::Utc>>,
+        eta: Option<chrono::NaiveDate>,
         allocations: collections::HashSet<OrderLine>,
     ) -> Self {
         Self {
             reference,
             sku,
             eta,
+            expiry: None,
             purchased_quantity: qty,
             allocations,
         }
     }
 
+    /// Sets the date the stock of this batch expires on.
+    pub fn with_expiry(mut self, expiry: Option<chrono::NaiveDate>) -> Self {
+        self.expiry = expiry;
+        self
+    }
+
+    /// Whether the stock of this batch is in the warehouse by `today`: it
+    /// has no ETA, or its ETA is not after `today`.
+    pub fn is_in_stock(&self, today: &chrono::NaiveDate) -> bool {
+        self.eta.is_none_or(|eta| eta <= *today)
+    }
+
     pub fn can_allocate(&self, line: &OrderLine) -> bool {
         self.sku == line.sku && self.available_quantity() >= line.qty
     }
 
-    pub fn allocate(&mut self, line: OrderLine) {
-        if self.can_allocate(&line) {
-            self.allocations.insert(line);
-        }
-        //    self.available_quantity -= line.qty;
+    /// Allocates `line` if it fits, returning whether it was added: `false`
+    /// too when this batch already holds it.
+    pub fn allocate(&mut self, line: OrderLine) -> bool {
+        self.can_allocate(&line) && self.allocations.insert(line)
     }
 
     pub fn reference(&self) -> &str {
@@ -66,14 +84,20 @@ impl Batch {
         self.purchased_quantity
     }
 
-    pub fn eta(&self) -> Option<&chrono::Date<chrono::Utc>> {
-        self.eta.as_ref()
+    pub fn eta(&self) -> Option<chrono::NaiveDate> {
+        self.eta
+    }
+
+    pub fn expiry(&self) -> Option<chrono::NaiveDate> {
+        self.expiry
     }
+
     pub fn allocations(&self) -> &collections::HashSet<OrderLine> {
         &self.allocations
     }
     pub fn available_quantity(&self) -> u32 {
-        self.purchased_quantity - self.allocated_quantity()
+        self.purchased_quantity
+            .saturating_sub(self.allocated_quantity())
     }
 
     pub fn allocated_quantity(&self) -> u32 {
@@ -87,6 +111,38 @@ impl Batch {
             self.allocations.remove(&line);
         }
     }
+
+    /// Allocates `qty` more of `sku` to `orderid`, growing the line the
+    /// order already has in this batch, if any, since a batch cannot hold
+    /// two equal lines.
+    fn allocate_fragment(&mut self, orderid: &str, sku: &str, qty: u32) {
+        let mut fragment = OrderLine::new(orderid.to_owned(), sku.to_owned(), qty);
+        if let Some(existing) = self.line_of(orderid, sku).cloned() {
+            self.allocations.remove(&existing);
+            fragment.qty += existing.qty;
+        }
+        self.allocations.insert(fragment);
+    }
+
+    fn line_of(&self, orderid: &str, sku: &str) -> Option<&OrderLine> {
+        self.allocations
+            .iter()
+            .find(|line| line.orderid == orderid && line.sku == sku)
+    }
+
+    pub fn change_purchased_quantity(&mut self, qty: u32) {
+        self.purchased_quantity = qty;
+    }
+
+    fn deallocate_largest(&mut self) -> Option<OrderLine> {
+        let line = self
+            .allocations
+            .iter()
+            .max_by(|a, b| a.qty.cmp(&b.qty).then_with(|| a.orderid.cmp(&b.orderid)))?
+            .clone();
+        self.allocations.remove(&line);
+        Some(line)
+    }
 }
 
 impl PartialEq for Batch {
@@ -95,31 +151,252 @@ impl PartialEq for Batch {
     }
 }
 
-pub fn sort_by_eta(a: &Batch, b: &Batch) -> Ordering {
-    if let Some(a_eta) = a.eta() {
-        if let Some(b_eta) = b.eta() {
-            return a_eta.cmp(b_eta);
-        } else {
-            return Ordering::Greater;
+/// In-stock batches first (see [`Batch::is_in_stock`]), then the earliest
+/// shipments.
+pub fn sort_by_eta(a: &Batch, b: &Batch, today: &chrono::NaiveDate) -> Ordering {
+    match (a.is_in_stock(today), b.is_in_stock(today)) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Less,
+        (false, true) => Ordering::Greater,
+        (false, false) => a.eta.cmp(&b.eta),
+    }
+}
+
+/// Aggregate owning every batch of a single SKU.
+///
+/// All allocations for a SKU go through its `Product`, which makes it the
+/// consistency boundary for invariants such as never overselling stock.
+/// The version number is the one the product was loaded with; repositories
+/// use it to detect concurrent modification when saving.
+///
+/// Every operation records the [`Event`]s it caused, which the service layer
+/// drains with [`Product::take_events`].
+///
+/// Lines are allocated from batches in the order of its
+/// [`AllocationStrategy`], [`EarliestEta`] unless told otherwise, as of the
+/// date of its [`Clock`].
+#[derive(Debug, Clone)]
+pub struct Product {
+    sku: String,
+    batches: Vec<Batch>,
+    version_number: u32,
+    events: Vec<Event>,
+    strategy: Arc<dyn AllocationStrategy>,
+    clock: Arc<dyn Clock>,
+}
+
+impl Product {
+    pub fn new(sku: String, batches: Vec<Batch>) -> Self {
+        Self::with_version(sku, batches, 0)
+    }
+
+    pub fn with_version(sku: String, batches: Vec<Batch>, version_number: u32) -> Self {
+        Self {
+            sku,
+            batches,
+            version_number,
+            events: Vec::new(),
+            strategy: Arc::new(EarliestEta),
+            clock: Arc::new(SystemClock::default()),
+        }
+    }
+
+    pub fn sku(&self) -> &str {
+        &self.sku
+    }
+
+    pub fn version_number(&self) -> u32 {
+        self.version_number
+    }
+
+    pub fn batches(&self) -> &[Batch] {
+        &self.batches
+    }
+
+    pub fn add_batch(&mut self, batch: Batch) {
+        self.batches.push(batch);
+    }
+
+    pub fn set_strategy(&mut self, strategy: Arc<dyn AllocationStrategy>) {
+        self.strategy = strategy;
+    }
+
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    pub fn take_events(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Allocates `line` to the first batch in strategy order that can take
+    /// it, returning that batch's reference.
+    ///
+    /// A line that is already allocated stays where it is: the reference of
+    /// its batch is returned and no event is raised.
+    pub fn allocate(&mut self, line: OrderLine) -> Result<String, Error> {
+        if let Some(batch) = self
+            .batches
+            .iter()
+            .find(|batch| batch.allocations.contains(&line))
+        {
+            return Ok(batch.reference.clone());
         }
-    } else {
-        if b.eta().is_none() {
-            return Ordering::Equal;
-        } else {
-            return Ordering::Less;
+        let (strategy, today) = (&self.strategy, self.clock.today());
+        self.batches
+            .sort_by(|a, b| strategy.compare(a, b, &line, &today));
+        for batch in self
+            .batches
+            .iter_mut()
+            .filter(|batch| strategy.accepts(batch, &today))
+        {
+            if batch.allocate(line.clone()) {
+                self.events.push(Event::Allocated {
+                    orderid: line.orderid,
+                    sku: line.sku,
+                    qty: line.qty,
+                    batchref: batch.reference.clone(),
+                });
+                return Ok(batch.reference.clone());
+            }
         }
+        self.events.push(Event::OutOfStock {
+            sku: line.sku.clone(),
+        });
+        Err(Error::OutOfStock(line.sku))
     }
-}
-pub fn allocate<'a>(line: OrderLine, batches: &'a mut [Batch]) -> Result<&'a str, Error> {
-    // let mut batches = batches.to_vec();
-    batches.sort_by(sort_by_eta);
-    for batch in batches {
-        if batch.can_allocate(&line) {
-            batch.allocate(line);
-            return Ok(&batch.reference);
+
+    /// Allocates `line` across as many batches as it takes, in strategy order,
+    /// returning the `(batchref, qty)` fragments it was split into.
+    ///
+    /// Each fragment raises its own `Allocated` event; a fragment landing in
+    /// a batch that already holds a line of the order for the SKU is added
+    /// to that line. Nothing is allocated unless the batches can take the
+    /// whole line between them.
+    pub fn allocate_split(&mut self, line: OrderLine) -> Result<Vec<(String, u32)>, Error> {
+        let (strategy, today) = (&self.strategy, self.clock.today());
+        self.batches
+            .sort_by(|a, b| strategy.compare(a, b, &line, &today));
+        let available: u32 = self
+            .batches
+            .iter()
+            .filter(|batch| batch.sku == line.sku && strategy.accepts(batch, &today))
+            .map(Batch::available_quantity)
+            .sum();
+        if available < line.qty {
+            self.events.push(Event::OutOfStock {
+                sku: line.sku.clone(),
+            });
+            return Err(Error::OutOfStock(line.sku));
+        }
+
+        let mut fragments = Vec::new();
+        let mut remaining = line.qty;
+        for batch in self
+            .batches
+            .iter_mut()
+            .filter(|batch| batch.sku == line.sku && strategy.accepts(batch, &today))
+        {
+            let qty = remaining.min(batch.available_quantity());
+            if qty == 0 {
+                continue;
+            }
+            let batchref = batch.reference.clone();
+            self.events.push(Event::Allocated {
+                orderid: line.orderid.clone(),
+                sku: line.sku.clone(),
+                qty,
+                batchref: batchref.clone(),
+            });
+            batch.allocate_fragment(&line.orderid, &line.sku, qty);
+            fragments.push((batchref, qty));
+            remaining -= qty;
+            if remaining == 0 {
+                break;
+            }
+        }
+        Ok(fragments)
+    }
+
+    /// Removes `line` from whichever batch holds it, returning that batch's
+    /// reference, or `None` if the line is not allocated.
+    ///
+    /// A line split by [`allocate_split`](Self::allocate_split) is held by
+    /// no single batch: when the order's lines for the SKU add up to `line`,
+    /// they are all removed and the first batch holding one is returned.
+    pub fn deallocate(&mut self, line: OrderLine) -> Option<String> {
+        let holds_line = |batch: &Batch| batch.allocations.contains(&line);
+        let fragments: Vec<(usize, OrderLine)> = match self.batches.iter().position(holds_line) {
+            Some(index) => vec![(index, line.clone())],
+            None => {
+                let fragments: Vec<_> = self
+                    .batches
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(index, batch)| {
+                        Some((index, batch.line_of(&line.orderid, &line.sku)?.clone()))
+                    })
+                    .collect();
+                let total: u32 = fragments.iter().map(|(_, fragment)| fragment.qty).sum();
+                if fragments.is_empty() || total != line.qty {
+                    return None;
+                }
+                fragments
+            }
+        };
+        for (index, fragment) in &fragments {
+            let batch = &mut self.batches[*index];
+            batch.deallocate(fragment.clone());
+            self.events.push(Event::Deallocated {
+                orderid: fragment.orderid.clone(),
+                sku: fragment.sku.clone(),
+                qty: fragment.qty,
+                batchref: batch.reference.clone(),
+            });
+        }
+        Some(self.batches[fragments[0].0].reference.clone())
+    }
+
+    /// Sets the purchased quantity of the batch `reference`, returning `false`
+    /// if this product has no such batch.
+    ///
+    /// When the batch can no longer hold all its allocations, its largest
+    /// lines are deallocated until it can, and then allocated to the other
+    /// batches of this product.
+    pub fn change_batch_quantity(&mut self, reference: &str, qty: u32) -> bool {
+        let batch = match self.batches.iter_mut().find(|b| b.reference == reference) {
+            Some(batch) => batch,
+            None => return false,
+        };
+        batch.change_purchased_quantity(qty);
+        self.events.push(Event::BatchQuantityChanged {
+            batchref: reference.to_owned(),
+            qty,
+        });
+
+        let mut overflowing = Vec::new();
+        while batch.allocated_quantity() > batch.purchased_quantity() {
+            let line = batch
+                .deallocate_largest()
+                .expect("an over-allocated batch has allocations");
+            self.events.push(Event::Deallocated {
+                orderid: line.orderid.clone(),
+                sku: line.sku.clone(),
+                qty: line.qty,
+                batchref: reference.to_owned(),
+            });
+            overflowing.push(line);
+        }
+        for line in overflowing {
+            // A line that cannot be reallocated is recorded as `OutOfStock`.
+            let _ = self.allocate(line);
         }
+        true
     }
-    Err(Error::OutOfStock(line.sku))
 }
 
 #[derive(Debug, PartialEq)]
@@ -139,11 +416,35 @@ impl OrderLine {
     pub fn new(orderid: String, sku: String, qty: u32) -> Self {
         Self { orderid, sku, qty }
     }
+
+    pub fn orderid(&self) -> &str {
+        &self.orderid
+    }
+
+    pub fn sku(&self) -> &str {
+        &self.sku
+    }
+
+    pub fn qty(&self) -> u32 {
+        self.qty
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::ManualClock;
+
+    fn today() -> chrono::NaiveDate {
+        chrono::NaiveDate::from_ymd_opt(2011, 1, 1).unwrap()
+    }
+
+    /// A product whose clock is frozen at `today()`.
+    fn product(sku: String, batches: Vec<Batch>) -> Product {
+        let mut product = Product::new(sku, batches);
+        product.set_clock(Arc::new(ManualClock::new(today())));
+        product
+    }
 
     #[test]
     fn allocating_to_a_batch_reduces_the_available_quantity() {
@@ -160,14 +461,14 @@ mod tests {
                 "batch-001".to_owned(),
                 sku.to_owned(),
                 batch_qty,
-                Some(chrono::Utc::today()),
+                Some(today()),
             ),
             OrderLine::new("order-123".to_owned(), sku.to_owned(), line_qty),
         )
     }
 
-    fn tomorrow() -> Option<chrono::Date<chrono::Utc>> {
-        Some(chrono::Utc::today() + chrono::Duration::days(1))
+    fn tomorrow() -> Option<chrono::NaiveDate> {
+        Some(today() + chrono::Duration::days(1))
     }
 
     #[test]
@@ -194,7 +495,7 @@ mod tests {
             "batch-001".to_owned(),
             "UNCOMFORTABLE-CHAIR".to_owned(),
             100,
-            Some(chrono::Utc::today()),
+            Some(today()),
         );
         let different_sku_line =
             OrderLine::new("order-123".to_owned(), "EXPENSIVE-TOASTER".to_owned(), 10);
@@ -222,7 +523,7 @@ mod tests {
             "in-stock-batch".to_owned(),
             "RETRO-CLOCK".to_owned(),
             100,
-            Some(chrono::Utc::today()),
+            Some(today()),
         );
         let shipment_batch = Batch::new(
             "shipment-batch".to_owned(),
@@ -232,25 +533,419 @@ mod tests {
         );
         let line = OrderLine::new("oref".to_owned(), "RETRO-CLOCK".to_owned(), 10);
 
-        let mut batches = vec![in_stock_batch, shipment_batch];
-        let res = allocate(line, &mut batches);
+        let mut product = product(
+            "RETRO-CLOCK".to_owned(),
+            vec![shipment_batch, in_stock_batch],
+        );
+        let res = product.allocate(line);
+
+        assert_eq!(res, Ok("in-stock-batch".to_owned()));
+
+        assert_eq!(product.batches()[0].available_quantity(), 90);
+        assert_eq!(product.batches()[1].available_quantity(), 100);
+    }
+
+    #[test]
+    fn batches_whose_eta_has_passed_are_in_stock() {
+        let arrived = Batch::new(
+            "arrived-batch".to_owned(),
+            "RETRO-CLOCK".to_owned(),
+            100,
+            Some(today() - chrono::Duration::days(3)),
+        );
+        let in_stock_batch = Batch::new(
+            "in-stock-batch".to_owned(),
+            "RETRO-CLOCK".to_owned(),
+            100,
+            None,
+        );
+        assert!(arrived.is_in_stock(&today()));
+        let mut product = product("RETRO-CLOCK".to_owned(), vec![arrived, in_stock_batch]);
+        let line = OrderLine::new("oref".to_owned(), "RETRO-CLOCK".to_owned(), 10);
+
+        // Both are in stock, so neither is preferred to the other.
+        assert_eq!(product.allocate(line), Ok("arrived-batch".to_owned()));
+    }
+
+    #[test]
+    fn shipments_come_in_stock_as_the_clock_advances() {
+        let clock = Arc::new(ManualClock::new(today()));
+        let shipment = Batch::new(
+            "shipment-batch".to_owned(),
+            "RETRO-CLOCK".to_owned(),
+            100,
+            Some(today() + chrono::Duration::days(2)),
+        );
+        assert!(!shipment.is_in_stock(&clock.today()));
 
-        assert_eq!(res, Ok("in-stock-batch"));
+        clock.advance(chrono::Duration::days(2));
 
-        assert_eq!(batches[0].available_quantity(), 90);
-        assert_eq!(batches[1].available_quantity(), 100);
+        assert!(shipment.is_in_stock(&clock.today()));
     }
 
     #[test]
     fn allocate_returns_outofstock_if_cannot_allocate() {
         let (batch, line) = make_batch_and_line("SMALL-FORK", 10, 10);
-        let mut batches = vec![batch];
-        allocate(line, &mut batches).expect("");
+        let mut product = product("SMALL-FORK".to_owned(), vec![batch]);
+        product.allocate(line).expect("");
+
+        let res = product.allocate(OrderLine::new(
+            "order2".to_owned(),
+            "SMALL-FORK".to_owned(),
+            1,
+        ));
+        assert_eq!(res, Err(Error::OutOfStock("SMALL-FORK".to_owned())));
+    }
 
-        let res = allocate(
-            OrderLine::new("order2".to_owned(), "SMALL-FORK".to_owned(), 1),
-            &mut batches,
+    #[test]
+    fn prefers_earlier_batches() {
+        let earliest = Batch::new(
+            "speedy-batch".to_owned(),
+            "MINIMALIST-SPOON".to_owned(),
+            100,
+            Some(today()),
         );
-        assert_eq!(res, Err(Error::OutOfStock("SMALL-FORK".to_owned())));
+        let medium = Batch::new(
+            "normal-batch".to_owned(),
+            "MINIMALIST-SPOON".to_owned(),
+            100,
+            tomorrow(),
+        );
+        let latest = Batch::new(
+            "slow-batch".to_owned(),
+            "MINIMALIST-SPOON".to_owned(),
+            100,
+            Some(today() + chrono::Duration::days(7)),
+        );
+        let mut product = product(
+            "MINIMALIST-SPOON".to_owned(),
+            vec![latest, medium, earliest],
+        );
+        let line = OrderLine::new("order1".to_owned(), "MINIMALIST-SPOON".to_owned(), 10);
+
+        assert_eq!(product.allocate(line), Ok("speedy-batch".to_owned()));
+
+        let available: Vec<(&str, u32)> = product
+            .batches()
+            .iter()
+            .map(|b| (b.reference(), b.available_quantity()))
+            .collect();
+        assert_eq!(
+            available,
+            vec![
+                ("speedy-batch", 90),
+                ("normal-batch", 100),
+                ("slow-batch", 100)
+            ]
+        );
+    }
+
+    #[test]
+    fn records_allocated_event() {
+        let batch = Batch::new(
+            "batchref".to_owned(),
+            "RETRO-LAMPSHADE".to_owned(),
+            100,
+            None,
+        );
+        let mut product = product("RETRO-LAMPSHADE".to_owned(), vec![batch]);
+        let line = OrderLine::new("oref".to_owned(), "RETRO-LAMPSHADE".to_owned(), 10);
+
+        product.allocate(line).expect("allocate");
+
+        assert_eq!(
+            product.take_events(),
+            vec![Event::Allocated {
+                orderid: "oref".to_owned(),
+                sku: "RETRO-LAMPSHADE".to_owned(),
+                qty: 10,
+                batchref: "batchref".to_owned(),
+            }]
+        );
+        assert!(product.events().is_empty());
+    }
+
+    #[test]
+    fn allocating_an_allocated_line_returns_its_batch_without_an_event() {
+        let in_stock = Batch::new("in-stock".to_owned(), "MINIMAL-SHELF".to_owned(), 10, None);
+        let shipment = Batch::new(
+            "shipment".to_owned(),
+            "MINIMAL-SHELF".to_owned(),
+            10,
+            tomorrow(),
+        );
+        let mut product = product("MINIMAL-SHELF".to_owned(), vec![in_stock, shipment]);
+        let line = OrderLine::new("order1".to_owned(), "MINIMAL-SHELF".to_owned(), 8);
+        product.allocate(line.clone()).expect("allocate");
+        product.take_events();
+
+        // The line would no longer fit in stock: it must not move to the
+        // shipment.
+        assert_eq!(product.allocate(line), Ok("in-stock".to_owned()));
+
+        assert!(product.events().is_empty());
+        assert_eq!(product.batches()[0].available_quantity(), 2);
+        assert_eq!(product.batches()[1].available_quantity(), 10);
+    }
+
+    #[test]
+    fn batches_report_whether_a_line_was_added() {
+        let (mut batch, line) = make_batch_and_line("ANGULAR-DESK", 20, 2);
+
+        assert!(batch.allocate(line.clone()));
+        assert!(!batch.allocate(line));
+    }
+
+    #[test]
+    fn records_out_of_stock_event_if_cannot_allocate() {
+        let batch = Batch::new("batch1".to_owned(), "SMALL-FORK".to_owned(), 10, None);
+        let mut product = product("SMALL-FORK".to_owned(), vec![batch]);
+        product
+            .allocate(OrderLine::new(
+                "order1".to_owned(),
+                "SMALL-FORK".to_owned(),
+                10,
+            ))
+            .expect("allocate");
+
+        let res = product.allocate(OrderLine::new(
+            "order2".to_owned(),
+            "SMALL-FORK".to_owned(),
+            1,
+        ));
+
+        assert!(res.is_err());
+        assert_eq!(
+            product.events().last(),
+            Some(&Event::OutOfStock {
+                sku: "SMALL-FORK".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn allocate_split_fills_a_line_from_several_batches_in_eta_order() {
+        let later = Batch::new("later".to_owned(), "BIG-SOFA".to_owned(), 10, tomorrow());
+        let earlier = Batch::new("earlier".to_owned(), "BIG-SOFA".to_owned(), 10, None);
+        let mut product = product("BIG-SOFA".to_owned(), vec![later, earlier]);
+        let line = OrderLine::new("order1".to_owned(), "BIG-SOFA".to_owned(), 15);
+
+        let res = product.allocate_split(line);
+
+        assert_eq!(
+            res,
+            Ok(vec![("earlier".to_owned(), 10), ("later".to_owned(), 5)])
+        );
+        assert_eq!(product.batches()[0].available_quantity(), 0);
+        assert_eq!(product.batches()[1].available_quantity(), 5);
+        assert_eq!(
+            product.take_events(),
+            vec![
+                Event::Allocated {
+                    orderid: "order1".to_owned(),
+                    sku: "BIG-SOFA".to_owned(),
+                    qty: 10,
+                    batchref: "earlier".to_owned(),
+                },
+                Event::Allocated {
+                    orderid: "order1".to_owned(),
+                    sku: "BIG-SOFA".to_owned(),
+                    qty: 5,
+                    batchref: "later".to_owned(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn allocate_split_uses_a_single_batch_when_one_can_take_the_line() {
+        let (batch, line) = make_batch_and_line("BIG-SOFA", 20, 15);
+        let mut product = product("BIG-SOFA".to_owned(), vec![batch]);
+
+        assert_eq!(
+            product.allocate_split(line),
+            Ok(vec![("batch-001".to_owned(), 15)])
+        );
+    }
+
+    #[test]
+    fn allocate_split_adds_a_fragment_to_the_line_a_batch_already_holds() {
+        let earlier = Batch::new("earlier".to_owned(), "BIG-SOFA".to_owned(), 10, None);
+        let later = Batch::new("later".to_owned(), "BIG-SOFA".to_owned(), 10, tomorrow());
+        let mut product = product("BIG-SOFA".to_owned(), vec![earlier, later]);
+        product
+            .allocate(OrderLine::new(
+                "order1".to_owned(),
+                "BIG-SOFA".to_owned(),
+                4,
+            ))
+            .expect("allocate");
+
+        let res = product.allocate_split(OrderLine::new(
+            "order1".to_owned(),
+            "BIG-SOFA".to_owned(),
+            10,
+        ));
+
+        assert_eq!(
+            res,
+            Ok(vec![("earlier".to_owned(), 6), ("later".to_owned(), 4)])
+        );
+        assert_eq!(product.batches()[0].available_quantity(), 0);
+        assert_eq!(product.batches()[1].available_quantity(), 6);
+    }
+
+    #[test]
+    fn a_split_line_can_be_deallocated_as_a_whole() {
+        let earlier = Batch::new("earlier".to_owned(), "BIG-SOFA".to_owned(), 10, None);
+        let later = Batch::new("later".to_owned(), "BIG-SOFA".to_owned(), 10, tomorrow());
+        let mut product = product("BIG-SOFA".to_owned(), vec![earlier, later]);
+        let line = OrderLine::new("order1".to_owned(), "BIG-SOFA".to_owned(), 15);
+        product
+            .allocate_split(line.clone())
+            .expect("allocate split");
+        product.take_events();
+
+        assert_eq!(product.deallocate(line.clone()), Some("earlier".to_owned()));
+        assert_eq!(product.deallocate(line), None);
+
+        assert_eq!(
+            product.take_events(),
+            vec![
+                Event::Deallocated {
+                    orderid: "order1".to_owned(),
+                    sku: "BIG-SOFA".to_owned(),
+                    qty: 10,
+                    batchref: "earlier".to_owned(),
+                },
+                Event::Deallocated {
+                    orderid: "order1".to_owned(),
+                    sku: "BIG-SOFA".to_owned(),
+                    qty: 5,
+                    batchref: "later".to_owned(),
+                },
+            ]
+        );
+        assert_eq!(product.batches()[0].available_quantity(), 10);
+        assert_eq!(product.batches()[1].available_quantity(), 10);
+    }
+
+    #[test]
+    fn allocate_split_allocates_nothing_if_the_batches_cannot_take_the_whole_line() {
+        let first = Batch::new("first".to_owned(), "BIG-SOFA".to_owned(), 10, None);
+        let second = Batch::new("second".to_owned(), "BIG-SOFA".to_owned(), 4, tomorrow());
+        let mut product = product("BIG-SOFA".to_owned(), vec![first, second]);
+        let line = OrderLine::new("order1".to_owned(), "BIG-SOFA".to_owned(), 15);
+
+        let res = product.allocate_split(line);
+
+        assert_eq!(res, Err(Error::OutOfStock("BIG-SOFA".to_owned())));
+        assert!(product
+            .batches()
+            .iter()
+            .all(|batch| batch.allocated_quantity() == 0));
+        assert_eq!(
+            product.take_events(),
+            vec![Event::OutOfStock {
+                sku: "BIG-SOFA".to_owned()
+            }]
+        );
+    }
+
+    #[test]
+    fn records_deallocated_event() {
+        let batch = Batch::new("batch1".to_owned(), "SMALL-FORK".to_owned(), 10, None);
+        let mut product = product("SMALL-FORK".to_owned(), vec![batch]);
+        let line = OrderLine::new("order1".to_owned(), "SMALL-FORK".to_owned(), 4);
+        product.allocate(line.clone()).expect("allocate");
+        product.take_events();
+
+        assert_eq!(product.deallocate(line.clone()), Some("batch1".to_owned()));
+        assert_eq!(product.deallocate(line), None);
+
+        assert_eq!(
+            product.take_events(),
+            vec![Event::Deallocated {
+                orderid: "order1".to_owned(),
+                sku: "SMALL-FORK".to_owned(),
+                qty: 4,
+                batchref: "batch1".to_owned(),
+            }]
+        );
+        assert_eq!(product.batches()[0].available_quantity(), 10);
+    }
+
+    #[test]
+    fn change_batch_quantity_deallocates_and_reallocates_overflowing_lines() {
+        let early = Batch::new("early".to_owned(), "INDIFFERENT-TABLE".to_owned(), 50, None);
+        let later = Batch::new(
+            "later".to_owned(),
+            "INDIFFERENT-TABLE".to_owned(),
+            50,
+            tomorrow(),
+        );
+        let mut product = product("INDIFFERENT-TABLE".to_owned(), vec![early, later]);
+        let line1 = OrderLine::new("order1".to_owned(), "INDIFFERENT-TABLE".to_owned(), 20);
+        let line2 = OrderLine::new("order2".to_owned(), "INDIFFERENT-TABLE".to_owned(), 20);
+        product.allocate(line1).expect("allocate");
+        product.allocate(line2).expect("allocate");
+        product.take_events();
+
+        assert!(product.change_batch_quantity("early", 25));
+
+        assert_eq!(
+            product.take_events(),
+            vec![
+                Event::BatchQuantityChanged {
+                    batchref: "early".to_owned(),
+                    qty: 25,
+                },
+                Event::Deallocated {
+                    orderid: "order2".to_owned(),
+                    sku: "INDIFFERENT-TABLE".to_owned(),
+                    qty: 20,
+                    batchref: "early".to_owned(),
+                },
+                Event::Allocated {
+                    orderid: "order2".to_owned(),
+                    sku: "INDIFFERENT-TABLE".to_owned(),
+                    qty: 20,
+                    batchref: "later".to_owned(),
+                },
+            ]
+        );
+        assert_eq!(product.batches()[0].available_quantity(), 5);
+        assert_eq!(product.batches()[1].available_quantity(), 30);
+    }
+
+    #[test]
+    fn change_batch_quantity_records_out_of_stock_for_lines_that_do_not_fit_elsewhere() {
+        let batch = Batch::new(
+            "batch1".to_owned(),
+            "INDIFFERENT-TABLE".to_owned(),
+            50,
+            None,
+        );
+        let mut product = product("INDIFFERENT-TABLE".to_owned(), vec![batch]);
+        let line = OrderLine::new("order1".to_owned(), "INDIFFERENT-TABLE".to_owned(), 20);
+        product.allocate(line).expect("allocate");
+
+        product.change_batch_quantity("batch1", 10);
+
+        assert_eq!(
+            product.events().last(),
+            Some(&Event::OutOfStock {
+                sku: "INDIFFERENT-TABLE".to_owned()
+            })
+        );
+        assert_eq!(product.batches()[0].allocated_quantity(), 0);
+    }
+
+    #[test]
+    fn change_batch_quantity_ignores_unknown_batches() {
+        let mut product = product("INDIFFERENT-TABLE".to_owned(), Vec::new());
+
+        assert!(!product.change_batch_quantity("batch1", 10));
+        assert!(product.events().is_empty());
     }
 }
@@ -1,7 +1,67 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
 use crate::model;
 
-pub trait Repository {
-    fn add(&self, batch: model::Batch);
+#[derive(Debug, Clone, thiserror::Error, PartialEq)]
+pub enum RepositoryError {
+    #[error("Product '{sku}' was modified concurrently (loaded version {version_number})")]
+    Concurrency { sku: String, version_number: u32 },
+    #[error("Store is locked by a concurrent transaction: {0}")]
+    Busy(String),
+    #[error("Not found: {0}")]
+    NotFound(String),
+    #[error("Already stored: {0}")]
+    Duplicate(String),
+    #[error("Rejected by the store: {0}")]
+    Rejected(String),
+    #[error("Repository unavailable: {0}")]
+    Unavailable(String),
+    #[error("Stored data is corrupt: {0}")]
+    Corrupt(String),
+}
+
+/// Port through which the service layer loads and stores batches and the
+/// products they belong to.
+#[async_trait]
+pub trait Repository: Send + Sync {
+    async fn add(&self, batch: model::Batch) -> Result<(), RepositoryError>;
+
+    async fn get(&self, reference: &str) -> Result<Option<model::Batch>, RepositoryError>;
+
+    async fn list(&self) -> Result<Vec<model::Batch>, RepositoryError>;
+
+    /// Loads the product with every batch of `sku`, or `None` if there are
+    /// no such batches.
+    async fn get_by_sku(&self, sku: &str) -> Result<Option<model::Product>, RepositoryError>;
+
+    /// Persists the batches and allocations of `product` and bumps its version.
+    ///
+    /// Fails with [`RepositoryError::Concurrency`] if the stored version no
+    /// longer matches the one the product was loaded with.
+    async fn save(&self, product: &model::Product) -> Result<(), RepositoryError>;
+}
+
+#[async_trait]
+impl<T: Repository + ?Sized> Repository for Arc<T> {
+    async fn add(&self, batch: model::Batch) -> Result<(), RepositoryError> {
+        (**self).add(batch).await
+    }
+
+    async fn get(&self, reference: &str) -> Result<Option<model::Batch>, RepositoryError> {
+        (**self).get(reference).await
+    }
+
+    async fn list(&self) -> Result<Vec<model::Batch>, RepositoryError> {
+        (**self).list().await
+    }
+
+    async fn get_by_sku(&self, sku: &str) -> Result<Option<model::Product>, RepositoryError> {
+        (**self).get_by_sku(sku).await
+    }
 
-    fn get(&self, reference: i32) -> model::Batch;
+    async fn save(&self, product: &model::Product) -> Result<(), RepositoryError> {
+        (**self).save(product).await
+    }
 }
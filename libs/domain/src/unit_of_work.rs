@@ -0,0 +1,85 @@
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+
+use crate::{
+    events::Event,
+    model,
+    repository::{Repository, RepositoryError},
+};
+
+/// A transaction spanning everything done through its repositories.
+///
+/// Nothing is durable until [`UnitOfWork::commit`] is called; dropping an
+/// uncommitted unit of work rolls its changes back.
+#[async_trait]
+pub trait UnitOfWork: Send {
+    type Products: Repository;
+
+    fn products(&self) -> &Self::Products;
+
+    async fn commit(&mut self) -> Result<(), RepositoryError>;
+
+    /// Drains the events raised by the products saved in committed work.
+    fn collect_new_events(&mut self) -> Vec<Event>;
+}
+
+/// Starts a fresh [`UnitOfWork`] for every message the service layer handles.
+#[async_trait]
+pub trait UnitOfWorkFactory: Send + Sync {
+    type UnitOfWork: UnitOfWork;
+
+    async fn begin(&self) -> Result<Self::UnitOfWork, RepositoryError>;
+}
+
+/// Repository wrapper remembering the events of every product it saved, so
+/// that a unit of work can hand them out once it commits.
+pub struct TrackingRepository<R> {
+    inner: R,
+    events: Mutex<Vec<Event>>,
+}
+
+impl<R> TrackingRepository<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            events: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn inner(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn take_events(&self) -> Vec<Event> {
+        std::mem::take(&mut *self.events.lock().unwrap())
+    }
+}
+
+#[async_trait]
+impl<R: Repository> Repository for TrackingRepository<R> {
+    async fn add(&self, batch: model::Batch) -> Result<(), RepositoryError> {
+        self.inner.add(batch).await
+    }
+
+    async fn get(&self, reference: &str) -> Result<Option<model::Batch>, RepositoryError> {
+        self.inner.get(reference).await
+    }
+
+    async fn list(&self) -> Result<Vec<model::Batch>, RepositoryError> {
+        self.inner.list().await
+    }
+
+    async fn get_by_sku(&self, sku: &str) -> Result<Option<model::Product>, RepositoryError> {
+        self.inner.get_by_sku(sku).await
+    }
+
+    async fn save(&self, product: &model::Product) -> Result<(), RepositoryError> {
+        self.inner.save(product).await?;
+        self.events
+            .lock()
+            .unwrap()
+            .extend(product.events().iter().cloned());
+        Ok(())
+    }
+}
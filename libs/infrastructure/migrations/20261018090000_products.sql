@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS products
+(
+    sku              STRING(255) PRIMARY KEY NOT NULL,
+    version_number   INTEGER                 NOT NULL DEFAULT 0
+);
+
+INSERT INTO products (sku)
+SELECT DISTINCT sku FROM batches;
@@ -0,0 +1,16 @@
+CREATE TABLE IF NOT EXISTS allocations_view
+(
+    id         INTEGER PRIMARY KEY NOT NULL,
+    orderid    STRING(255)         NOT NULL,
+    sku        STRING(255)         NOT NULL,
+    qty        INTEGER             NOT NULL,
+    batchref   STRING(255)         NOT NULL,
+    eta        DATETIME
+);
+CREATE INDEX IF NOT EXISTS allocations_view_orderid ON allocations_view (orderid);
+
+INSERT INTO allocations_view (orderid, sku, qty, batchref, eta)
+SELECT order_lines.orderid, order_lines.sku, order_lines.qty, batches.reference, batches.eta
+FROM allocations
+JOIN order_lines ON order_lines.id = allocations.orderline_id
+JOIN batches ON batches.id = allocations.batch_id;
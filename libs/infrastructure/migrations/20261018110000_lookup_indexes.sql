@@ -0,0 +1,5 @@
+CREATE INDEX IF NOT EXISTS batches_sku ON batches (sku);
+CREATE INDEX IF NOT EXISTS batches_reference ON batches (reference);
+CREATE INDEX IF NOT EXISTS order_lines_orderid_sku ON order_lines (orderid, sku);
+CREATE INDEX IF NOT EXISTS allocations_batch_id ON allocations (batch_id);
+CREATE INDEX IF NOT EXISTS allocations_orderline_id ON allocations (orderline_id);
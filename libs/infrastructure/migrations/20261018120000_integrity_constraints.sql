@@ -0,0 +1,55 @@
+-- SQLite cannot add constraints to existing tables, so the write tables are
+-- rebuilt. Renaming first rewrites the foreign keys of the old allocations
+-- to the old tables, which keeps dropping them safe with foreign keys on.
+ALTER TABLE allocations RENAME TO allocations_old;
+ALTER TABLE order_lines RENAME TO order_lines_old;
+ALTER TABLE batches RENAME TO batches_old;
+
+CREATE TABLE batches
+(
+    id                    INTEGER PRIMARY KEY NOT NULL,
+    reference             STRING(255)         NOT NULL UNIQUE,
+    sku                   STRING(255)         NOT NULL,
+    _purchased_quantity   INTEGER             NOT NULL CHECK (_purchased_quantity >= 0),
+    eta                   DATETIME
+);
+CREATE TABLE order_lines
+(
+    id         INTEGER PRIMARY KEY NOT NULL,
+    sku        STRING(255)         NOT NULL,
+    qty        INTEGER             NOT NULL CHECK (qty > 0),
+    orderid    STRING(255)         NOT NULL
+);
+CREATE TABLE allocations
+(
+    id             INTEGER PRIMARY KEY NOT NULL,
+    orderline_id   INTEGER             NOT NULL UNIQUE,
+    batch_id       INTEGER             NOT NULL,
+    FOREIGN KEY (orderline_id)
+        REFERENCES order_lines (id) ON DELETE CASCADE,
+    FOREIGN KEY (batch_id)
+        REFERENCES batches (id) ON DELETE CASCADE
+);
+
+-- Rows breaking the new constraints fail the migration rather than being
+-- dropped with the stock or allocations they hold; they have to be fixed by
+-- hand first.
+INSERT INTO batches (id, reference, sku, _purchased_quantity, eta)
+SELECT id, reference, sku, _purchased_quantity, eta
+FROM batches_old;
+
+INSERT INTO order_lines (id, sku, qty, orderid)
+SELECT id, sku, qty, orderid
+FROM order_lines_old;
+
+INSERT INTO allocations (id, orderline_id, batch_id)
+SELECT id, orderline_id, batch_id
+FROM allocations_old;
+
+DROP TABLE allocations_old;
+DROP TABLE order_lines_old;
+DROP TABLE batches_old;
+
+CREATE INDEX IF NOT EXISTS batches_sku ON batches (sku);
+CREATE INDEX IF NOT EXISTS order_lines_orderid_sku ON order_lines (orderid, sku);
+CREATE INDEX IF NOT EXISTS allocations_batch_id ON allocations (batch_id);
@@ -0,0 +1,3 @@
+-- The date the stock of a batch expires on, for first-expired-first-out
+-- allocation; NULL when it does not expire.
+ALTER TABLE batches ADD COLUMN expiry DATE;
@@ -0,0 +1,57 @@
+-- Batch dates are calendar dates, stored as ISO-8601 'YYYY-MM-DD' text.
+-- Older rows may hold a date with a time or zone suffix, or a Unix
+-- timestamp; they are cut down to their date. A value that still is not a
+-- date is kept as is, so that the CHECK fails the migration rather than
+-- silently dropping the date of a shipment.
+--
+-- SQLite cannot change the declared type of a column, so batches is rebuilt
+-- the same way as by the integrity constraints migration; allocations is
+-- rebuilt with it to point its foreign key at the new table.
+ALTER TABLE allocations RENAME TO allocations_old;
+ALTER TABLE batches RENAME TO batches_old;
+
+CREATE TABLE batches
+(
+    id                    INTEGER PRIMARY KEY NOT NULL,
+    reference             STRING(255)         NOT NULL UNIQUE,
+    sku                   STRING(255)         NOT NULL,
+    _purchased_quantity   INTEGER             NOT NULL CHECK (_purchased_quantity >= 0),
+    eta                   DATE                CHECK (eta IS NULL OR eta IS date(eta)),
+    expiry                DATE                CHECK (expiry IS NULL OR expiry IS date(expiry))
+);
+CREATE TABLE allocations
+(
+    id             INTEGER PRIMARY KEY NOT NULL,
+    orderline_id   INTEGER             NOT NULL UNIQUE,
+    batch_id       INTEGER             NOT NULL,
+    FOREIGN KEY (orderline_id)
+        REFERENCES order_lines (id) ON DELETE CASCADE,
+    FOREIGN KEY (batch_id)
+        REFERENCES batches (id) ON DELETE CASCADE
+);
+
+INSERT INTO batches (id, reference, sku, _purchased_quantity, eta, expiry)
+SELECT id, reference, sku, _purchased_quantity,
+    CASE
+        WHEN typeof(eta) IN ('integer', 'real') THEN date(eta, 'unixepoch')
+        ELSE COALESCE(date(substr(eta, 1, 10)), eta)
+    END,
+    CASE
+        WHEN typeof(expiry) IN ('integer', 'real') THEN date(expiry, 'unixepoch')
+        ELSE COALESCE(date(substr(expiry, 1, 10)), expiry)
+    END
+FROM batches_old;
+
+INSERT INTO allocations (id, orderline_id, batch_id)
+SELECT id, orderline_id, batch_id
+FROM allocations_old;
+
+DROP TABLE allocations_old;
+DROP TABLE batches_old;
+
+CREATE INDEX IF NOT EXISTS batches_sku ON batches (sku);
+CREATE INDEX IF NOT EXISTS allocations_batch_id ON allocations (batch_id);
+
+-- The read model copies the ETA of the batch.
+UPDATE allocations_view
+SET eta = (SELECT batches.eta FROM batches WHERE batches.reference = allocations_view.batchref);
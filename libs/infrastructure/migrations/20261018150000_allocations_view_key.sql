@@ -0,0 +1,16 @@
+-- A row of the read model is the quantity of an order's SKU allocated to a
+-- batch. Rows duplicated by past events are merged before the key is added.
+UPDATE allocations_view
+SET qty = (
+    SELECT SUM(other.qty)
+    FROM allocations_view AS other
+    WHERE other.orderid = allocations_view.orderid
+    AND other.sku = allocations_view.sku
+    AND other.batchref = allocations_view.batchref
+);
+DELETE FROM allocations_view
+WHERE id NOT IN (
+    SELECT MIN(id) FROM allocations_view GROUP BY orderid, sku, batchref
+);
+
+CREATE UNIQUE INDEX allocations_view_line ON allocations_view (orderid, sku, batchref);
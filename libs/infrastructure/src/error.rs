@@ -0,0 +1,121 @@
+use domain::repository::RepositoryError;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Not found: {0}")]
+    NotFound(String),
+    #[error("Product '{sku}' was modified concurrently (loaded version {version_number})")]
+    Conflict { sku: String, version_number: u32 },
+    #[error("{kind} constraint violated: {message}")]
+    Constraint {
+        kind: ConstraintKind,
+        message: String,
+    },
+    #[error("Database is locked: {0}")]
+    Locked(#[source] sqlx::Error),
+    #[error("Database error: {0}")]
+    Database(#[source] sqlx::Error),
+    #[error("Cannot decode row: {0}")]
+    Decode(#[source] sqlx::Error),
+}
+
+/// Integrity constraint of the schema a write ran into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConstraintKind {
+    Unique,
+    Check,
+    ForeignKey,
+    NotNull,
+}
+
+impl ConstraintKind {
+    /// Classifies an extended SQLite result code.
+    fn from_sqlite_code(code: &str) -> Option<Self> {
+        match code {
+            // SQLITE_CONSTRAINT_UNIQUE, SQLITE_CONSTRAINT_PRIMARYKEY
+            "2067" | "1555" => Some(Self::Unique),
+            // SQLITE_CONSTRAINT_CHECK
+            "275" => Some(Self::Check),
+            // SQLITE_CONSTRAINT_FOREIGNKEY
+            "787" => Some(Self::ForeignKey),
+            // SQLITE_CONSTRAINT_NOTNULL
+            "1299" => Some(Self::NotNull),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ConstraintKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self {
+            Self::Unique => "Unique",
+            Self::Check => "Check",
+            Self::ForeignKey => "Foreign key",
+            Self::NotNull => "Not null",
+        };
+        f.write_str(kind)
+    }
+}
+
+/// Whether an extended SQLite result code is SQLITE_BUSY or SQLITE_LOCKED.
+///
+/// Two deferred transactions that both read a product and then write it
+/// deadlock on their locks; one of them fails with such a code and can be
+/// retried once it has rolled back.
+fn is_lock_conflict(code: &str) -> bool {
+    matches!(code.parse::<i32>().map(|code| code & 0xff), Ok(5 | 6))
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(err: sqlx::Error) -> Self {
+        match err {
+            sqlx::Error::Database(db_err)
+                if db_err.code().as_deref().is_some_and(is_lock_conflict) =>
+            {
+                Self::Locked(sqlx::Error::Database(db_err))
+            }
+            sqlx::Error::Database(db_err) => {
+                match db_err
+                    .code()
+                    .as_deref()
+                    .and_then(ConstraintKind::from_sqlite_code)
+                {
+                    Some(kind) => Self::Constraint {
+                        kind,
+                        message: db_err.message().to_owned(),
+                    },
+                    None => Self::Database(sqlx::Error::Database(db_err)),
+                }
+            }
+            sqlx::Error::RowNotFound => Self::NotFound("row".to_owned()),
+            sqlx::Error::ColumnDecode { .. }
+            | sqlx::Error::ColumnNotFound(_)
+            | sqlx::Error::ColumnIndexOutOfBounds { .. }
+            | sqlx::Error::Decode(_) => Self::Decode(err),
+            err => Self::Database(err),
+        }
+    }
+}
+
+impl From<Error> for RepositoryError {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::NotFound(what) => Self::NotFound(what),
+            Error::Conflict {
+                sku,
+                version_number,
+            } => Self::Concurrency {
+                sku,
+                version_number,
+            },
+            Error::Constraint {
+                kind: ConstraintKind::Unique,
+                message,
+            } => Self::Duplicate(message),
+            Error::Constraint { message, .. } => Self::Rejected(message),
+            Error::Locked(err) => Self::Busy(err.to_string()),
+            Error::Database(err) => Self::Unavailable(err.to_string()),
+            Error::Decode(err) => Self::Corrupt(err.to_string()),
+        }
+    }
+}
@@ -1,6 +1,21 @@
-use sqlx::sqlite::SqlitePool;
+use std::str::FromStr;
 
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool};
+
+mod error;
 pub mod repositories;
+pub mod unit_of_work;
+pub mod views;
+
+pub use error::{ConstraintKind, Error};
+
+/// Options for connections to the database at `url`, each enforcing foreign
+/// keys. The database file is created if it does not exist.
+pub fn connect_options(url: &str) -> Result<SqliteConnectOptions, Error> {
+    Ok(SqliteConnectOptions::from_str(url)?
+        .foreign_keys(true)
+        .create_if_missing(true))
+}
 
 pub async fn run_migrations(db: &SqlitePool) -> Result<(), Box<dyn std::error::Error>> {
     sqlx::migrate!("./migrations").run(db).await?;
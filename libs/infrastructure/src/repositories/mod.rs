@@ -1,3 +1,5 @@
 mod sqlx_batches;
 
-pub use sqlx_batches::SqlxRepository;
+pub use sqlx_batches::{
+    BatchFilter, BatchPage, BatchSummary, SqlxRepository, PRODUCT_BATCHES_QUERY,
+};
@@ -1,99 +1,512 @@
-use std::collections::HashSet;
+use std::{
+    collections::{HashMap, HashSet},
+    ops::{Deref, DerefMut},
+};
 
-use chrono::{Datelike, NaiveDate, NaiveDateTime};
-use domain::model;
-use sqlx::{sqlite::SqlitePool, Row};
+use async_trait::async_trait;
+use chrono::NaiveDate;
+use domain::{
+    model,
+    repository::{Repository, RepositoryError},
+};
+use futures_util::lock::{Mutex, MutexGuard};
 
+use crate::Error;
+use sqlx::{
+    pool::PoolConnection,
+    sqlite::{Sqlite, SqliteConnection, SqlitePool, SqliteRow},
+    Connection as _, Row, Transaction,
+};
+
+/// Repository backed by SQLite.
+///
+/// Created with [`SqlxRepository::new`], every call runs on its own pooled
+/// connection; inside a unit of work all calls share its transaction.
 pub struct SqlxRepository {
-    pool: SqlitePool,
+    conn: Connection,
+}
+
+enum Connection {
+    Pool(SqlitePool),
+    Transaction(Mutex<Option<Transaction<'static, Sqlite>>>),
+}
+
+enum ConnectionGuard<'a> {
+    Pool(PoolConnection<Sqlite>),
+    Transaction(MutexGuard<'a, Option<Transaction<'static, Sqlite>>>),
+}
+
+impl Deref for ConnectionGuard<'_> {
+    type Target = SqliteConnection;
+
+    fn deref(&self) -> &SqliteConnection {
+        match self {
+            Self::Pool(conn) => conn,
+            Self::Transaction(tx) => tx.as_ref().expect("transaction is still open"),
+        }
+    }
+}
+
+impl DerefMut for ConnectionGuard<'_> {
+    fn deref_mut(&mut self) -> &mut SqliteConnection {
+        match self {
+            Self::Pool(conn) => conn,
+            Self::Transaction(tx) => tx.as_mut().expect("transaction is still open"),
+        }
+    }
+}
+
+/// Narrows down [`SqlxRepository::list_page`]; unset fields match all batches.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BatchFilter {
+    pub sku: Option<String>,
+    pub eta_from: Option<NaiveDate>,
+    pub eta_to: Option<NaiveDate>,
+    /// Keeps only batches in the warehouse: without an ETA or arriving on
+    /// or before this date.
+    pub in_stock_on: Option<NaiveDate>,
+    pub has_availability: bool,
+}
+
+/// A batch with its quantities, as listed by [`SqlxRepository::list_page`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchSummary {
+    pub id: u32,
+    pub reference: String,
+    pub sku: String,
+    pub eta: Option<NaiveDate>,
+    pub purchased_quantity: u32,
+    pub allocated_quantity: u32,
+}
+
+impl BatchSummary {
+    pub fn available_quantity(&self) -> u32 {
+        self.purchased_quantity
+            .saturating_sub(self.allocated_quantity)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchPage {
+    pub batches: Vec<BatchSummary>,
+    /// Pass as `after` to fetch the next page; `None` on the last page.
+    pub next_after: Option<u32>,
 }
 
 impl SqlxRepository {
     pub fn new(pool: SqlitePool) -> Self {
-        Self { pool }
+        Self {
+            conn: Connection::Pool(pool),
+        }
     }
 
-    pub async fn add(&self, batch: model::Batch) {
-        const QUERY: &str = "INSERT INTO batches 
-            (reference, sku, _purchased_quantity, eta)
-            VALUES ($1, $2, $3, $4)";
-        sqlx::query(QUERY)
-            .bind(batch.reference())
-            .bind(batch.sku())
-            .bind(batch.purchased_quantity())
-            .bind(encode_date(batch.eta()))
-            .execute(&self.pool)
-            .await
-            .expect("repositories/sqlx_batches: inserting batch");
+    pub(crate) fn in_transaction(tx: Transaction<'static, Sqlite>) -> Self {
+        Self {
+            conn: Connection::Transaction(Mutex::new(Some(tx))),
+        }
     }
 
-    pub async fn get(&self, reference: &str) -> model::Batch {
-        const QUERY: &str = "
-            SELECT id, reference, sku, _purchased_quantity, eta
-            FROM batches
-            WHERE reference=$1
-        ";
-        const ALLOCATIONS_QUERY: &str = "
-            SELECT order_lines.sku, order_lines.qty, order_lines.orderid
-            FROM order_lines
-            LEFT JOIN allocations
-            ON order_lines.id = allocations.orderline_id
-            AND allocations.batch_id = $1
-        ";
-        let row = sqlx::query(QUERY)
-            .bind(reference)
-            .fetch_one(&self.pool)
-            .await
-            .expect("repositories/sqlx_batches: get batch");
-
-        let batch_id: u32 = row.try_get("id").unwrap();
-        let reference: String = row.try_get("reference").expect("");
-        let sku: String = row.try_get("sku").unwrap();
-        let purchased_quantity: u32 = row.try_get("_purchased_quantity").unwrap();
-        let eta: Option<chrono::Date<chrono::Utc>> = decode_date(row.try_get("eta").unwrap());
-
-        let mut allocations = HashSet::new();
-        let allocation_rows = sqlx::query(ALLOCATIONS_QUERY)
-            .bind(batch_id)
-            .fetch_all(&self.pool)
-            .await
-            .expect("");
-        for allocation_row in allocation_rows {
-            let allocation_sku: String = allocation_row.try_get("sku").unwrap();
-            let orderid: String = allocation_row.try_get("orderid").unwrap();
-            let qty: u32 = allocation_row.try_get("qty").unwrap();
-            allocations.insert(model::OrderLine::new(orderid, allocation_sku, qty));
+    /// Commits the transaction this repository is bound to.
+    pub(crate) async fn commit(&self) -> Result<(), Error> {
+        let tx = match &self.conn {
+            Connection::Transaction(tx) => tx.lock().await.take(),
+            Connection::Pool(_) => None,
+        };
+        if let Some(tx) = tx {
+            tx.commit().await?;
         }
-        model::Batch::with_allocations(reference, sku, purchased_quantity, eta, allocations)
+        Ok(())
     }
 
-    pub async fn list(&self) -> Vec<model::Batch> {
+    /// Lists up to `limit` batches matching `filter`, ordered by id and
+    /// starting after the batch with id `after`.
+    pub async fn list_page(
+        &self,
+        filter: &BatchFilter,
+        after: Option<u32>,
+        limit: u32,
+    ) -> Result<BatchPage, Error> {
         const QUERY: &str = "
-            SELECT reference, sku, _purchased_quantity, eta
+            SELECT batches.id, batches.reference, batches.sku, batches.eta,
+                   batches._purchased_quantity,
+                   COALESCE(SUM(order_lines.qty), 0) AS allocated_quantity
             FROM batches
+            LEFT JOIN allocations ON allocations.batch_id = batches.id
+            LEFT JOIN order_lines ON order_lines.id = allocations.orderline_id
+            WHERE batches.id > ?1
+            AND (?2 IS NULL OR batches.sku = ?2)
+            AND (?3 IS NULL OR batches.eta >= ?3)
+            AND (?4 IS NULL OR batches.eta <= ?4)
+            AND (?5 IS NULL OR batches.eta IS NULL OR batches.eta <= ?5)
+            GROUP BY batches.id
+            HAVING NOT ?6 OR batches._purchased_quantity > allocated_quantity
+            ORDER BY batches.id
+            LIMIT ?7
         ";
-
-        sqlx::query(QUERY)
-            .fetch_all(&self.pool)
-            .await
-            .expect("")
+        let mut conn = self.connection().await?;
+        let mut batches = sqlx::query(QUERY)
+            .bind(after.unwrap_or(0))
+            .bind(filter.sku.as_deref())
+            .bind(filter.eta_from)
+            .bind(filter.eta_to)
+            .bind(filter.in_stock_on)
+            .bind(filter.has_availability)
+            .bind(i64::from(limit) + 1)
+            .fetch_all(&mut *conn)
+            .await?
             .iter()
             .map(|row| {
-                model::Batch::new(
-                    row.get("reference"),
-                    row.get("sku"),
-                    row.get("_purchased_quantity"),
-                    decode_date(row.get("eta")),
-                )
+                Ok(BatchSummary {
+                    id: row.try_get("id")?,
+                    reference: row.try_get("reference")?,
+                    sku: row.try_get("sku")?,
+                    eta: row.try_get("eta")?,
+                    purchased_quantity: row.try_get("_purchased_quantity")?,
+                    allocated_quantity: row.try_get("allocated_quantity")?,
+                })
             })
-            .collect()
+            .collect::<Result<Vec<_>, Error>>()?;
+        let next_after = if batches.len() > limit as usize {
+            batches.truncate(limit as usize);
+            batches.last().map(|batch| batch.id)
+        } else {
+            None
+        };
+        Ok(BatchPage {
+            batches,
+            next_after,
+        })
+    }
+
+    /// The connection this repository runs on: its transaction, if any.
+    pub(crate) async fn acquire(
+        &self,
+    ) -> Result<impl DerefMut<Target = SqliteConnection> + '_, Error> {
+        self.connection().await
+    }
+
+    async fn connection(&self) -> Result<ConnectionGuard<'_>, Error> {
+        match &self.conn {
+            Connection::Pool(pool) => Ok(ConnectionGuard::Pool(pool.acquire().await?)),
+            Connection::Transaction(tx) => {
+                let tx = tx.lock().await;
+                if tx.is_none() {
+                    return Err(Error::Database(sqlx::Error::Protocol(
+                        "transaction already committed".to_owned(),
+                    )));
+                }
+                Ok(ConnectionGuard::Transaction(tx))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Repository for SqlxRepository {
+    async fn add(&self, batch: model::Batch) -> Result<(), RepositoryError> {
+        let mut conn = self.connection().await?;
+        let mut tx = conn.begin().await.map_err(Error::from)?;
+        let batch_id = insert_batch(&mut tx, &batch).await?;
+        for line in batch.allocations() {
+            insert_allocation(&mut tx, batch_id, line).await?;
+        }
+        tx.commit().await.map_err(Error::from)?;
+        Ok(())
     }
+
+    async fn get(&self, reference: &str) -> Result<Option<model::Batch>, RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(get_batch(&mut conn, reference).await?)
+    }
+
+    async fn list(&self) -> Result<Vec<model::Batch>, RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(list_batches(&mut conn).await?)
+    }
+
+    async fn get_by_sku(&self, sku: &str) -> Result<Option<model::Product>, RepositoryError> {
+        let mut conn = self.connection().await?;
+        Ok(get_product(&mut conn, sku).await?)
+    }
+
+    async fn save(&self, product: &model::Product) -> Result<(), RepositoryError> {
+        let mut conn = self.connection().await?;
+        let mut tx = conn.begin().await.map_err(Error::from)?;
+        save_product(&mut tx, product).await?;
+        tx.commit().await.map_err(Error::from)?;
+        Ok(())
+    }
+}
+
+async fn get_batch(
+    conn: &mut SqliteConnection,
+    reference: &str,
+) -> Result<Option<model::Batch>, Error> {
+    const QUERY: &str = "
+        SELECT id, reference, sku, _purchased_quantity, eta, expiry
+        FROM batches
+        WHERE reference=$1
+    ";
+    let rows = sqlx::query(QUERY)
+        .bind(reference)
+        .fetch_all(&mut *conn)
+        .await?;
+    Ok(load_batches(conn, rows).await?.pop())
+}
+
+async fn list_batches(conn: &mut SqliteConnection) -> Result<Vec<model::Batch>, Error> {
+    const QUERY: &str = "
+        SELECT id, reference, sku, _purchased_quantity, eta, expiry
+        FROM batches
+    ";
+    let rows = sqlx::query(QUERY).fetch_all(&mut *conn).await?;
+    load_batches(conn, rows).await
+}
+
+/// Selects the batches of the product whose SKU is bound to `$1`; public so
+/// that tests can check its query plan.
+pub const PRODUCT_BATCHES_QUERY: &str = "
+    SELECT id, reference, sku, _purchased_quantity, eta, expiry
+    FROM batches
+    WHERE sku=$1
+";
+
+async fn get_product(
+    conn: &mut SqliteConnection,
+    sku: &str,
+) -> Result<Option<model::Product>, Error> {
+    const VERSION_QUERY: &str = "SELECT version_number FROM products WHERE sku=$1";
+    let rows = sqlx::query(PRODUCT_BATCHES_QUERY)
+        .bind(sku)
+        .fetch_all(&mut *conn)
+        .await?;
+    if rows.is_empty() {
+        return Ok(None);
+    }
+    let version_number: u32 = match sqlx::query(VERSION_QUERY)
+        .bind(sku)
+        .fetch_optional(&mut *conn)
+        .await?
+    {
+        Some(row) => row.try_get("version_number")?,
+        None => 0,
+    };
+    let batches = load_batches(conn, rows).await?;
+    Ok(Some(model::Product::with_version(
+        sku.to_owned(),
+        batches,
+        version_number,
+    )))
+}
+
+async fn save_product(conn: &mut SqliteConnection, product: &model::Product) -> Result<(), Error> {
+    const BUMP_VERSION: &str = "
+        INSERT INTO products (sku, version_number)
+        VALUES ($1, $2 + 1)
+        ON CONFLICT (sku) DO UPDATE
+        SET version_number = excluded.version_number
+        WHERE products.version_number = $2
+    ";
+    let bumped = sqlx::query(BUMP_VERSION)
+        .bind(product.sku())
+        .bind(product.version_number())
+        .execute(&mut *conn)
+        .await?
+        .rows_affected();
+    if bumped == 0 {
+        return Err(Error::Conflict {
+            sku: product.sku().to_owned(),
+            version_number: product.version_number(),
+        });
+    }
+    for batch in product.batches() {
+        persist_batch(conn, batch).await?;
+    }
+    Ok(())
+}
+
+/// Rehydrates the batches in `rows`, fetching the order lines allocated to
+/// all of them with a single query.
+async fn load_batches(
+    conn: &mut SqliteConnection,
+    rows: Vec<SqliteRow>,
+) -> Result<Vec<model::Batch>, Error> {
+    const ALLOCATIONS_QUERY: &str = "
+        SELECT allocations.batch_id, order_lines.sku, order_lines.qty, order_lines.orderid
+        FROM allocations
+        JOIN order_lines ON order_lines.id = allocations.orderline_id
+        WHERE allocations.batch_id IN (SELECT value FROM json_each($1))
+    ";
+    if rows.is_empty() {
+        return Ok(Vec::new());
+    }
+    let batch_ids = rows
+        .iter()
+        .map(|row| row.try_get("id"))
+        .collect::<Result<Vec<u32>, _>>()?;
+    let mut allocations: HashMap<u32, HashSet<model::OrderLine>> = HashMap::new();
+    for row in sqlx::query(ALLOCATIONS_QUERY)
+        .bind(serde_json::to_string(&batch_ids).expect("ids serialize to json"))
+        .fetch_all(&mut *conn)
+        .await?
+    {
+        let batch_id: u32 = row.try_get("batch_id")?;
+        allocations
+            .entry(batch_id)
+            .or_default()
+            .insert(decode_order_line(&row)?);
+    }
+
+    rows.iter()
+        .zip(batch_ids)
+        .map(|(row, batch_id)| {
+            Ok(model::Batch::with_allocations(
+                row.try_get("reference")?,
+                row.try_get("sku")?,
+                row.try_get("_purchased_quantity")?,
+                row.try_get("eta")?,
+                allocations.remove(&batch_id).unwrap_or_default(),
+            )
+            .with_expiry(row.try_get("expiry")?))
+        })
+        .collect()
+}
+
+fn decode_order_line(row: &SqliteRow) -> Result<model::OrderLine, Error> {
+    Ok(model::OrderLine::new(
+        row.try_get("orderid")?,
+        row.try_get("sku")?,
+        row.try_get("qty")?,
+    ))
+}
+
+/// Writes `batch` and brings its persisted allocations in line with
+/// `batch.allocations()`, leaving untouched lines alone.
+async fn persist_batch(conn: &mut SqliteConnection, batch: &model::Batch) -> Result<(), Error> {
+    let batch_id = upsert_batch(conn, batch).await?;
+    let persisted = allocated_lines(conn, batch_id).await?;
+    for line in persisted.difference(batch.allocations()) {
+        delete_allocation(conn, batch_id, line).await?;
+    }
+    for line in batch.allocations().difference(&persisted) {
+        insert_allocation(conn, batch_id, line).await?;
+    }
+    Ok(())
+}
+
+async fn upsert_batch(conn: &mut SqliteConnection, batch: &model::Batch) -> Result<u32, Error> {
+    const SELECT_BATCH: &str = "SELECT id FROM batches WHERE reference=$1";
+    const UPDATE_BATCH: &str = "UPDATE batches SET _purchased_quantity=$1 WHERE id=$2";
+    let row = sqlx::query(SELECT_BATCH)
+        .bind(batch.reference())
+        .fetch_optional(&mut *conn)
+        .await?;
+    if let Some(row) = row {
+        let batch_id: u32 = row.try_get("id")?;
+        sqlx::query(UPDATE_BATCH)
+            .bind(batch.purchased_quantity())
+            .bind(batch_id)
+            .execute(&mut *conn)
+            .await?;
+        return Ok(batch_id);
+    }
+    insert_batch(conn, batch).await
+}
+
+/// Inserts `batch` without its allocations; fails with a unique constraint
+/// violation if its reference is taken.
+async fn insert_batch(conn: &mut SqliteConnection, batch: &model::Batch) -> Result<u32, Error> {
+    const INSERT_BATCH: &str = "INSERT INTO batches
+        (reference, sku, _purchased_quantity, eta, expiry)
+        VALUES ($1, $2, $3, $4, $5)";
+    let batch_id = sqlx::query(INSERT_BATCH)
+        .bind(batch.reference())
+        .bind(batch.sku())
+        .bind(batch.purchased_quantity())
+        .bind(batch.eta())
+        .bind(batch.expiry())
+        .execute(&mut *conn)
+        .await?
+        .last_insert_rowid();
+    Ok(batch_id as u32)
+}
+
+async fn allocated_lines(
+    conn: &mut SqliteConnection,
+    batch_id: u32,
+) -> Result<HashSet<model::OrderLine>, Error> {
+    const QUERY: &str = "
+        SELECT order_lines.sku, order_lines.qty, order_lines.orderid
+        FROM allocations
+        JOIN order_lines ON order_lines.id = allocations.orderline_id
+        WHERE allocations.batch_id = $1
+    ";
+    sqlx::query(QUERY)
+        .bind(batch_id)
+        .fetch_all(&mut *conn)
+        .await?
+        .iter()
+        .map(decode_order_line)
+        .collect()
 }
 
-fn encode_date(date: Option<&chrono::Date<chrono::Utc>>) -> Option<NaiveDate> {
-    date.map(|d| NaiveDate::from_ymd(d.year(), d.month(), d.day()))
+async fn insert_allocation(
+    conn: &mut SqliteConnection,
+    batch_id: u32,
+    line: &model::OrderLine,
+) -> Result<(), Error> {
+    const INSERT_ORDER_LINE: &str = "
+        INSERT INTO order_lines (orderid, sku, qty)
+        VALUES ($1, $2, $3)
+    ";
+    const INSERT_ALLOCATION: &str = "
+        INSERT INTO allocations (orderline_id, batch_id)
+        VALUES ($1, $2)
+    ";
+    let orderline_id = sqlx::query(INSERT_ORDER_LINE)
+        .bind(line.orderid())
+        .bind(line.sku())
+        .bind(line.qty())
+        .execute(&mut *conn)
+        .await?
+        .last_insert_rowid();
+    sqlx::query(INSERT_ALLOCATION)
+        .bind(orderline_id)
+        .bind(batch_id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
 }
 
-fn decode_date(date: Option<NaiveDate>) -> Option<chrono::Date<chrono::Utc>> {
-    date.map(|d| chrono::Date::from_utc(d, chrono::Utc))
+async fn delete_allocation(
+    conn: &mut SqliteConnection,
+    batch_id: u32,
+    line: &model::OrderLine,
+) -> Result<(), Error> {
+    const DELETE_ALLOCATION: &str = "
+        DELETE FROM allocations
+        WHERE batch_id = $1 AND orderline_id IN (
+            SELECT id FROM order_lines
+            WHERE orderid = $2 AND sku = $3 AND qty = $4
+        )
+    ";
+    const DELETE_ORPHANED_ORDER_LINES: &str = "
+        DELETE FROM order_lines
+        WHERE orderid = $1 AND sku = $2 AND qty = $3
+        AND id NOT IN (SELECT orderline_id FROM allocations)
+    ";
+    sqlx::query(DELETE_ALLOCATION)
+        .bind(batch_id)
+        .bind(line.orderid())
+        .bind(line.sku())
+        .bind(line.qty())
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query(DELETE_ORPHANED_ORDER_LINES)
+        .bind(line.orderid())
+        .bind(line.sku())
+        .bind(line.qty())
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
 }
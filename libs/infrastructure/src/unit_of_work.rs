@@ -0,0 +1,82 @@
+use std::ops::DerefMut;
+
+use async_trait::async_trait;
+use domain::{
+    events::Event,
+    repository::RepositoryError,
+    unit_of_work::{TrackingRepository, UnitOfWork, UnitOfWorkFactory},
+};
+use sqlx::sqlite::{SqliteConnection, SqlitePool};
+
+use crate::{repositories::SqlxRepository, Error};
+
+/// A write that changes nothing, run first in every unit of work.
+///
+/// sqlx only opens deferred transactions, which take the write lock at their
+/// first write: two units of work that both load a product and then save it
+/// deadlock, and one of them fails. Claiming the lock up front, as `BEGIN
+/// IMMEDIATE` would, makes concurrent units of work wait for each other.
+const CLAIM_WRITE_LOCK: &str = "UPDATE products SET version_number = version_number WHERE 0";
+
+/// Begins a [`SqlxUnitOfWork`] on a connection from the pool.
+#[derive(Clone)]
+pub struct SqlxUnitOfWorkFactory {
+    pool: SqlitePool,
+}
+
+impl SqlxUnitOfWorkFactory {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl UnitOfWorkFactory for SqlxUnitOfWorkFactory {
+    type UnitOfWork = SqlxUnitOfWork;
+
+    async fn begin(&self) -> Result<SqlxUnitOfWork, RepositoryError> {
+        let mut tx = self.pool.begin().await.map_err(Error::from)?;
+        sqlx::query(CLAIM_WRITE_LOCK)
+            .execute(&mut tx)
+            .await
+            .map_err(Error::from)?;
+        Ok(SqlxUnitOfWork {
+            products: TrackingRepository::new(SqlxRepository::in_transaction(tx)),
+            events: Vec::new(),
+        })
+    }
+}
+
+/// Unit of work over one SQLite transaction, rolled back when dropped
+/// uncommitted.
+pub struct SqlxUnitOfWork {
+    products: TrackingRepository<SqlxRepository>,
+    events: Vec<Event>,
+}
+
+impl SqlxUnitOfWork {
+    /// The connection of this unit of work, for writes to tables outside
+    /// its repositories, such as the read model, that commit with it.
+    pub async fn connection(&self) -> Result<impl DerefMut<Target = SqliteConnection> + '_, Error> {
+        self.products.inner().acquire().await
+    }
+}
+
+#[async_trait]
+impl UnitOfWork for SqlxUnitOfWork {
+    type Products = TrackingRepository<SqlxRepository>;
+
+    fn products(&self) -> &Self::Products {
+        &self.products
+    }
+
+    async fn commit(&mut self) -> Result<(), RepositoryError> {
+        self.products.inner().commit().await?;
+        self.events.extend(self.products.take_events());
+        Ok(())
+    }
+
+    fn collect_new_events(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.events)
+    }
+}
@@ -0,0 +1,132 @@
+//! Read model: the denormalized `allocations_view` table, kept up to date
+//! from `Allocated`/`Deallocated` events and read without touching the
+//! write tables.
+use chrono::NaiveDate;
+use sqlx::{
+    sqlite::{SqliteConnection, SqlitePool},
+    Connection, Row,
+};
+
+use crate::Error;
+
+/// Where one line of an order was allocated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Allocation {
+    pub sku: String,
+    pub qty: u32,
+    pub batchref: String,
+    pub eta: Option<NaiveDate>,
+}
+
+/// Lists the allocations of `orderid`, empty when the order is unknown.
+pub async fn allocations(pool: &SqlitePool, orderid: &str) -> Result<Vec<Allocation>, Error> {
+    const QUERY: &str = "
+        SELECT sku, qty, batchref, eta
+        FROM allocations_view
+        WHERE orderid = $1
+        ORDER BY sku, batchref
+    ";
+    sqlx::query(QUERY)
+        .bind(orderid)
+        .fetch_all(pool)
+        .await?
+        .iter()
+        .map(|row| {
+            Ok(Allocation {
+                sku: row.try_get("sku")?,
+                qty: row.try_get("qty")?,
+                batchref: row.try_get("batchref")?,
+                eta: row.try_get("eta")?,
+            })
+        })
+        .collect()
+}
+
+/// Records that `qty` more of `sku` was allocated to `orderid` from the
+/// batch `batchref`.
+///
+/// Runs on `conn` so that a unit of work updates the view in its own
+/// transaction.
+pub async fn add_allocation(
+    conn: &mut SqliteConnection,
+    orderid: &str,
+    sku: &str,
+    qty: u32,
+    batchref: &str,
+) -> Result<(), Error> {
+    const UPSERT: &str = "
+        INSERT INTO allocations_view (orderid, sku, qty, batchref, eta)
+        SELECT ?1, ?2, ?3, reference, eta
+        FROM batches
+        WHERE reference = ?4
+        ON CONFLICT (orderid, sku, batchref)
+        DO UPDATE SET qty = allocations_view.qty + excluded.qty, eta = excluded.eta
+    ";
+    let inserted = sqlx::query(UPSERT)
+        .bind(orderid)
+        .bind(sku)
+        .bind(qty)
+        .bind(batchref)
+        .execute(conn)
+        .await?
+        .rows_affected();
+    if inserted == 0 {
+        return Err(Error::NotFound(format!("batch '{}'", batchref)));
+    }
+    Ok(())
+}
+
+/// Records that `qty` of `sku` was deallocated from the batch `batchref`,
+/// forgetting the row once nothing is left; does nothing if there is none.
+pub async fn remove_allocation(
+    conn: &mut SqliteConnection,
+    orderid: &str,
+    sku: &str,
+    qty: u32,
+    batchref: &str,
+) -> Result<(), Error> {
+    const SUBTRACT: &str = "
+        UPDATE allocations_view
+        SET qty = qty - ?3
+        WHERE orderid = ?1 AND sku = ?2 AND batchref = ?4
+    ";
+    const DELETE_EMPTY: &str = "
+        DELETE FROM allocations_view
+        WHERE orderid = ?1 AND sku = ?2 AND batchref = ?3 AND qty <= 0
+    ";
+    sqlx::query(SUBTRACT)
+        .bind(orderid)
+        .bind(sku)
+        .bind(qty)
+        .bind(batchref)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query(DELETE_EMPTY)
+        .bind(orderid)
+        .bind(sku)
+        .bind(batchref)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Recomputes `allocations_view` from the write tables, for when it has
+/// drifted because an event handler failed. Returns the number of rows.
+pub async fn rebuild_allocations_view(pool: &SqlitePool) -> Result<u64, Error> {
+    const CLEAR: &str = "DELETE FROM allocations_view";
+    const FILL: &str = "
+        INSERT INTO allocations_view (orderid, sku, qty, batchref, eta)
+        SELECT order_lines.orderid, order_lines.sku, SUM(order_lines.qty),
+               batches.reference, batches.eta
+        FROM allocations
+        JOIN order_lines ON order_lines.id = allocations.orderline_id
+        JOIN batches ON batches.id = allocations.batch_id
+        GROUP BY order_lines.orderid, order_lines.sku, batches.reference
+    ";
+    let mut conn = pool.acquire().await?;
+    let mut tx = conn.begin().await?;
+    sqlx::query(CLEAR).execute(&mut tx).await?;
+    let rows = sqlx::query(FILL).execute(&mut tx).await?.rows_affected();
+    tx.commit().await?;
+    Ok(rows)
+}
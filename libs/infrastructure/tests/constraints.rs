@@ -0,0 +1,197 @@
+use domain::{
+    model,
+    repository::{Repository, RepositoryError},
+};
+use infrastructure::{repositories::SqlxRepository, ConstraintKind, Error};
+use sqlx::{
+    migrate::Migrator,
+    sqlite::{SqlitePool, SqlitePoolOptions},
+    Row,
+};
+
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+/// First migration adding the integrity constraints.
+const CONSTRAINTS_VERSION: i64 = 20261018120000;
+
+#[tokio::test]
+async fn adding_a_batch_with_a_taken_reference_is_a_duplicate() {
+    let session = setup_db().await;
+    let repo = SqlxRepository::new(session);
+    let batch = || model::Batch::new("batch1".to_owned(), "GENERIC-SOFA".to_owned(), 10, None);
+    repo.add(batch()).await.expect("add batch");
+
+    let res = repo.add(batch()).await;
+
+    assert!(
+        matches!(res, Err(RepositoryError::Duplicate(_))),
+        "{:?}",
+        res
+    );
+}
+
+#[tokio::test]
+async fn an_order_line_cannot_be_allocated_twice() {
+    let session = setup_db().await;
+    insert_stock(&session).await;
+
+    let res = sqlx::query("INSERT INTO allocations (orderline_id, batch_id) VALUES (1, 2)")
+        .execute(&session)
+        .await;
+
+    assert!(matches!(
+        res.map_err(Error::from),
+        Err(Error::Constraint {
+            kind: ConstraintKind::Unique,
+            ..
+        })
+    ));
+}
+
+#[tokio::test]
+async fn quantities_must_be_positive() {
+    let session = setup_db().await;
+
+    let res = sqlx::query("INSERT INTO order_lines (orderid, sku, qty) VALUES ('o1', 'SOFA', 0)")
+        .execute(&session)
+        .await;
+
+    assert!(matches!(
+        res.map_err(Error::from),
+        Err(Error::Constraint {
+            kind: ConstraintKind::Check,
+            ..
+        })
+    ));
+}
+
+#[tokio::test]
+async fn foreign_keys_are_enforced_and_deletes_cascade() {
+    let options = infrastructure::connect_options("sqlite::memory:").expect("connect options");
+    let session = SqlitePoolOptions::new()
+        .connect_with(options)
+        .await
+        .expect("Failed to connect to db");
+    MIGRATOR.run(&session).await.expect("running migrations");
+    insert_stock(&session).await;
+
+    let res = sqlx::query("INSERT INTO allocations (orderline_id, batch_id) VALUES (2, 99)")
+        .execute(&session)
+        .await;
+    assert!(matches!(
+        res.map_err(Error::from),
+        Err(Error::Constraint {
+            kind: ConstraintKind::ForeignKey,
+            ..
+        })
+    ));
+
+    sqlx::query("DELETE FROM batches WHERE id = 1")
+        .execute(&session)
+        .await
+        .expect("delete batch");
+    assert_eq!(count(&session, "allocations").await, 0);
+}
+
+#[tokio::test]
+async fn constraints_migration_keeps_valid_rows() {
+    let session = migrated_up_to_constraints().await;
+    sqlx::query(
+        "INSERT INTO batches (id, reference, sku, _purchased_quantity, eta) VALUES
+            (1, 'batch1', 'GENERIC-SOFA', 10, null),
+            (2, 'batch2', 'GENERIC-SOFA', 10, '2011-01-01');
+        INSERT INTO order_lines (id, orderid, sku, qty) VALUES
+            (1, 'order1', 'GENERIC-SOFA', 2);
+        INSERT INTO allocations (id, orderline_id, batch_id) VALUES (1, 1, 2);",
+    )
+    .execute(&session)
+    .await
+    .expect("insert legacy rows");
+
+    MIGRATOR.run(&session).await.expect("running migrations");
+
+    assert_eq!(count(&session, "batches").await, 2);
+    let batch = SqlxRepository::new(session)
+        .get("batch2")
+        .await
+        .expect("get batch")
+        .expect("batch exists");
+    assert_eq!(batch.available_quantity(), 8);
+}
+
+#[tokio::test]
+async fn constraints_migration_fails_on_violating_rows() {
+    let violations = [
+        "INSERT INTO batches (id, reference, sku, _purchased_quantity, eta) VALUES
+            (1, 'batch1', 'GENERIC-SOFA', 10, null),
+            (2, 'batch1', 'GENERIC-SOFA', 10, null);",
+        "INSERT INTO batches (id, reference, sku, _purchased_quantity, eta) VALUES
+            (1, 'batch1', 'GENERIC-SOFA', -1, null);",
+        "INSERT INTO order_lines (id, orderid, sku, qty) VALUES
+            (1, 'order1', 'GENERIC-SOFA', 0);",
+        "INSERT INTO batches (id, reference, sku, _purchased_quantity, eta) VALUES
+            (1, 'batch1', 'GENERIC-SOFA', 10, null),
+            (2, 'batch2', 'GENERIC-SOFA', 10, null);
+        INSERT INTO order_lines (id, orderid, sku, qty) VALUES
+            (1, 'order1', 'GENERIC-SOFA', 2);
+        INSERT INTO allocations (id, orderline_id, batch_id) VALUES (1, 1, 1), (2, 1, 2);",
+    ];
+    for rows in violations {
+        let session = migrated_up_to_constraints().await;
+        sqlx::query(rows)
+            .execute(&session)
+            .await
+            .expect("insert legacy rows");
+
+        assert!(MIGRATOR.run(&session).await.is_err(), "{}", rows);
+    }
+}
+
+async fn migrated_up_to_constraints() -> SqlitePool {
+    let session = SqlitePool::connect("sqlite::memory:")
+        .await
+        .expect("Failed to connect to db");
+    let before = Migrator {
+        migrations: MIGRATOR
+            .migrations
+            .iter()
+            .filter(|m| m.version < CONSTRAINTS_VERSION)
+            .cloned()
+            .collect(),
+        ignore_missing: false,
+        locking: true,
+    };
+    before.run(&session).await.expect("running old migrations");
+    session
+}
+
+async fn insert_stock(session: &SqlitePool) {
+    sqlx::query(
+        "INSERT INTO batches (id, reference, sku, _purchased_quantity, eta) VALUES
+            (1, 'batch1', 'GENERIC-SOFA', 10, null),
+            (2, 'batch2', 'GENERIC-SOFA', 10, null);
+        INSERT INTO order_lines (id, orderid, sku, qty) VALUES
+            (1, 'order1', 'GENERIC-SOFA', 2),
+            (2, 'order2', 'GENERIC-SOFA', 3);
+        INSERT INTO allocations (orderline_id, batch_id) VALUES (1, 1);",
+    )
+    .execute(session)
+    .await
+    .expect("insert stock");
+}
+
+async fn count(session: &SqlitePool, table: &str) -> i64 {
+    sqlx::query(&format!("SELECT COUNT(*) AS n FROM {}", table))
+        .fetch_one(session)
+        .await
+        .expect("count rows")
+        .get("n")
+}
+
+async fn setup_db() -> SqlitePool {
+    let db = SqlitePool::connect("sqlite::memory:")
+        .await
+        .expect("Failed to connect to db");
+    MIGRATOR.run(&db).await.expect("running migrations");
+    db
+}
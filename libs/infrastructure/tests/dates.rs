@@ -0,0 +1,96 @@
+use domain::repository::Repository;
+use infrastructure::{repositories::SqlxRepository, views};
+use sqlx::{migrate::Migrator, sqlite::SqlitePool, Row};
+
+static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+/// Migration normalising batch dates to ISO-8601.
+const ISO_DATES_VERSION: i64 = 20261018140000;
+
+fn date(year: i32, month: u32, day: u32) -> chrono::NaiveDate {
+    chrono::NaiveDate::from_ymd_opt(year, month, day).unwrap()
+}
+
+async fn migrated_up_to_iso_dates() -> SqlitePool {
+    let session = SqlitePool::connect("sqlite::memory:")
+        .await
+        .expect("Failed to connect to db");
+    let before = Migrator {
+        migrations: MIGRATOR
+            .migrations
+            .iter()
+            .filter(|m| m.version < ISO_DATES_VERSION)
+            .cloned()
+            .collect(),
+        ignore_missing: false,
+        locking: true,
+    };
+    before.run(&session).await.expect("running old migrations");
+    session
+}
+
+#[tokio::test]
+async fn iso_dates_migration_normalises_stored_dates() {
+    let session = migrated_up_to_iso_dates().await;
+    sqlx::query(
+        "INSERT INTO batches (id, reference, sku, _purchased_quantity, eta, expiry) VALUES
+            (1, 'batch1', 'GENERIC-SOFA', 10, '2011-01-02 00:00:00', '2011-03-01UTC'),
+            (2, 'batch2', 'GENERIC-SOFA', 10, '2011-01-03T00:00:00+02:00', null),
+            (3, 'batch3', 'GENERIC-SOFA', 10, 1294185600, null),
+            (4, 'batch4', 'GENERIC-SOFA', 10, null, null);
+        INSERT INTO order_lines (id, orderid, sku, qty) VALUES
+            (1, 'order1', 'GENERIC-SOFA', 2);
+        INSERT INTO allocations (id, orderline_id, batch_id) VALUES (1, 1, 1);
+        INSERT INTO allocations_view (orderid, sku, qty, batchref, eta) VALUES
+            ('order1', 'GENERIC-SOFA', 2, 'batch1', '2011-01-02 00:00:00');",
+    )
+    .execute(&session)
+    .await
+    .expect("insert legacy rows");
+
+    MIGRATOR.run(&session).await.expect("running migrations");
+
+    let stored: Vec<Option<String>> = sqlx::query("SELECT eta FROM batches ORDER BY id")
+        .fetch_all(&session)
+        .await
+        .expect("select etas")
+        .iter()
+        .map(|row| row.get("eta"))
+        .collect();
+    assert_eq!(
+        stored,
+        vec![
+            Some("2011-01-02".to_owned()),
+            Some("2011-01-03".to_owned()),
+            Some("2011-01-05".to_owned()),
+            None
+        ]
+    );
+    let repo = SqlxRepository::new(session.clone());
+    let batch = repo
+        .get("batch1")
+        .await
+        .expect("get batch")
+        .expect("batch exists");
+    assert_eq!(batch.eta(), Some(date(2011, 1, 2)));
+    assert_eq!(batch.expiry(), Some(date(2011, 3, 1)));
+    assert_eq!(batch.available_quantity(), 8);
+    let allocations = views::allocations(&session, "order1")
+        .await
+        .expect("read allocations");
+    assert_eq!(allocations[0].eta, Some(date(2011, 1, 2)));
+}
+
+#[tokio::test]
+async fn iso_dates_migration_fails_on_values_that_are_not_dates() {
+    let session = migrated_up_to_iso_dates().await;
+    sqlx::query(
+        "INSERT INTO batches (id, reference, sku, _purchased_quantity, eta) VALUES
+            (1, 'batch1', 'GENERIC-SOFA', 10, 'next tuesday');",
+    )
+    .execute(&session)
+    .await
+    .expect("insert legacy rows");
+
+    assert!(MIGRATOR.run(&session).await.is_err());
+}
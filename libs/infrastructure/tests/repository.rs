@@ -1,8 +1,8 @@
 use std::collections::HashSet;
 
-use chrono;
 use domain::model;
-use infrastructure::repositories::SqlxRepository;
+use domain::repository::{Repository, RepositoryError};
+use infrastructure::repositories::{BatchFilter, SqlxRepository, PRODUCT_BATCHES_QUERY};
 use sqlx::{sqlite::SqlitePool, Row};
 
 use futures_util::TryStreamExt;
@@ -16,7 +16,11 @@ async fn repository_can_retrieve_a_batch_with_allocations() {
     insert_allocation(&session, orderline_id, batch1_id).await;
 
     let repo = SqlxRepository::new(session);
-    let retrieved = repo.get("batch1").await;
+    let retrieved = repo
+        .get("batch1")
+        .await
+        .expect("get batch")
+        .expect("batch exists");
 
     let expected = model::Batch::new("batch1".to_owned(), "GENERIC-SOFA".to_owned(), 100, None);
 
@@ -35,13 +39,39 @@ async fn repository_can_retrieve_a_batch_with_allocations() {
     assert_eq!(retrieved.allocations(), &expected_allocations);
 }
 
+#[tokio::test]
+async fn repository_returns_none_for_unknown_batch() {
+    let session = setup_db().await;
+    insert_batch(&session, "batch1").await;
+
+    let repo = SqlxRepository::new(session);
+    let retrieved = repo.get("batch2").await.expect("get batch");
+
+    assert_eq!(retrieved, None);
+}
+
+#[tokio::test]
+async fn repository_round_trips_batch_expiry() -> Result<(), Box<dyn std::error::Error>> {
+    let session = setup_db().await;
+    let expiry = chrono::NaiveDate::from_ymd_opt(2011, 4, 11).unwrap();
+    let batch = model::Batch::new("batch1".to_owned(), "GOURMET-CHEESE".to_owned(), 10, None)
+        .with_expiry(Some(expiry));
+
+    let repo = SqlxRepository::new(session);
+    repo.add(batch).await?;
+    let retrieved = repo.get("batch1").await?.expect("batch exists");
+
+    assert_eq!(retrieved.expiry(), Some(expiry));
+    Ok(())
+}
+
 #[tokio::test]
 async fn repository_can_save_a_batch() -> Result<(), Box<dyn std::error::Error>> {
     let session = setup_db().await;
     let batch = model::Batch::new("batch1".to_owned(), "RUSTY-SOAPDISH".to_owned(), 100, None);
 
     let repo = SqlxRepository::new(session.clone());
-    repo.add(batch).await;
+    repo.add(batch).await?;
     // session.commit();
 
     let mut rows =
@@ -60,6 +90,431 @@ async fn repository_can_save_a_batch() -> Result<(), Box<dyn std::error::Error>>
     Ok(())
 }
 
+#[tokio::test]
+async fn repository_can_retrieve_a_product_with_its_batches() {
+    let session = setup_db().await;
+    insert_batch(&session, "batch1").await;
+    insert_batch(&session, "batch2").await;
+
+    let repo = SqlxRepository::new(session);
+    let product = repo
+        .get_by_sku("GENERIC-SOFA")
+        .await
+        .expect("get product")
+        .expect("product exists");
+
+    assert_eq!(product.sku(), "GENERIC-SOFA");
+    let mut references: Vec<&str> = product.batches().iter().map(|b| b.reference()).collect();
+    references.sort_unstable();
+    assert_eq!(references, vec!["batch1", "batch2"]);
+}
+
+#[tokio::test]
+async fn repository_returns_none_for_unknown_product() {
+    let session = setup_db().await;
+    insert_batch(&session, "batch1").await;
+
+    let repo = SqlxRepository::new(session);
+
+    let product = repo.get_by_sku("UNKNOWN-SOFA").await.expect("get product");
+
+    assert!(product.is_none());
+}
+
+#[tokio::test]
+async fn repository_persists_allocations_when_saving_a_product() {
+    let session = setup_db().await;
+    insert_batch(&session, "batch1").await;
+    let repo = SqlxRepository::new(session);
+
+    let mut product = repo
+        .get_by_sku("GENERIC-SOFA")
+        .await
+        .expect("get product")
+        .expect("product exists");
+    product
+        .allocate(model::OrderLine::new(
+            "order1".to_owned(),
+            "GENERIC-SOFA".to_owned(),
+            12,
+        ))
+        .expect("allocate");
+    repo.save(&product).await.expect("save product");
+
+    let retrieved = repo
+        .get_by_sku("GENERIC-SOFA")
+        .await
+        .expect("get product")
+        .expect("product exists");
+    assert_eq!(retrieved.version_number(), product.version_number() + 1);
+    assert_eq!(retrieved.batches()[0].available_quantity(), 88);
+}
+
+#[tokio::test]
+async fn repository_rejects_saving_a_stale_product() {
+    let session = setup_db().await;
+    insert_batch(&session, "batch1").await;
+    let repo = SqlxRepository::new(session);
+
+    let mut first = repo
+        .get_by_sku("GENERIC-SOFA")
+        .await
+        .expect("get product")
+        .expect("product exists");
+    let mut second = first.clone();
+    first
+        .allocate(model::OrderLine::new(
+            "order1".to_owned(),
+            "GENERIC-SOFA".to_owned(),
+            10,
+        ))
+        .expect("allocate");
+    second
+        .allocate(model::OrderLine::new(
+            "order2".to_owned(),
+            "GENERIC-SOFA".to_owned(),
+            10,
+        ))
+        .expect("allocate");
+
+    repo.save(&first).await.expect("save first");
+    let res = repo.save(&second).await;
+
+    assert_eq!(
+        res,
+        Err(RepositoryError::Concurrency {
+            sku: "GENERIC-SOFA".to_owned(),
+            version_number: 0,
+        })
+    );
+    let retrieved = repo
+        .get_by_sku("GENERIC-SOFA")
+        .await
+        .expect("get product")
+        .expect("product exists");
+    assert_eq!(retrieved.batches()[0].available_quantity(), 90);
+}
+
+#[tokio::test]
+async fn repository_persists_allocations_when_adding_a_batch() {
+    let session = setup_db().await;
+    let mut batch = model::Batch::new("batch1".to_owned(), "GENERIC-SOFA".to_owned(), 100, None);
+    let line = model::OrderLine::new("order1".to_owned(), "GENERIC-SOFA".to_owned(), 12);
+    batch.allocate(line.clone());
+    let repo = SqlxRepository::new(session.clone());
+
+    repo.add(batch).await.expect("add batch");
+
+    let retrieved = repo
+        .get("batch1")
+        .await
+        .expect("get batch")
+        .expect("batch exists");
+    assert_eq!(retrieved.allocations(), &HashSet::from([line]));
+    assert_eq!(count(&session, "order_lines").await, 1);
+}
+
+#[tokio::test]
+async fn repository_deletes_deallocated_lines_when_saving_a_product() {
+    let session = setup_db().await;
+    let orderline_id = insert_order_line(&session).await;
+    let batch_id = insert_batch(&session, "batch1").await;
+    insert_allocation(&session, orderline_id, batch_id).await;
+    let repo = SqlxRepository::new(session.clone());
+
+    let mut product = repo
+        .get_by_sku("GENERIC-SOFA")
+        .await
+        .expect("get product")
+        .expect("product exists");
+    product.deallocate(model::OrderLine::new(
+        "order1".to_owned(),
+        "GENERIC-SOFA".to_owned(),
+        12,
+    ));
+    product
+        .allocate(model::OrderLine::new(
+            "order2".to_owned(),
+            "GENERIC-SOFA".to_owned(),
+            5,
+        ))
+        .expect("allocate");
+    repo.save(&product).await.expect("save product");
+
+    let retrieved = repo
+        .get("batch1")
+        .await
+        .expect("get batch")
+        .expect("batch exists");
+    assert_eq!(
+        retrieved.allocations(),
+        &HashSet::from([model::OrderLine::new(
+            "order2".to_owned(),
+            "GENERIC-SOFA".to_owned(),
+            5,
+        )])
+    );
+    assert_eq!(count(&session, "allocations").await, 1);
+    assert_eq!(count(&session, "order_lines").await, 1);
+}
+
+#[tokio::test]
+async fn repository_leaves_unchanged_allocations_alone_when_saving() {
+    let session = setup_db().await;
+    let orderline_id = insert_order_line(&session).await;
+    let batch_id = insert_batch(&session, "batch1").await;
+    insert_allocation(&session, orderline_id, batch_id).await;
+    let repo = SqlxRepository::new(session.clone());
+
+    let product = repo
+        .get_by_sku("GENERIC-SOFA")
+        .await
+        .expect("get product")
+        .expect("product exists");
+    repo.save(&product).await.expect("save product");
+
+    let row = sqlx::query("SELECT orderline_id FROM allocations")
+        .fetch_one(&session)
+        .await
+        .expect("select allocation");
+    let persisted_id: u32 = row.get("orderline_id");
+    assert_eq!(persisted_id, orderline_id);
+    assert_eq!(count(&session, "order_lines").await, 1);
+}
+
+#[tokio::test]
+async fn repository_reports_undecodable_rows_as_corrupt() {
+    let session = setup_db().await;
+    insert_batch(&session, "batch1").await;
+    sqlx::query("UPDATE batches SET _purchased_quantity = 5000000000")
+        .execute(&session)
+        .await
+        .expect("corrupt batch");
+    let repo = SqlxRepository::new(session);
+
+    let res = repo.get("batch1").await;
+
+    assert!(matches!(res, Err(RepositoryError::Corrupt(_))), "{:?}", res);
+}
+
+#[tokio::test]
+async fn repository_reports_database_failures_as_unavailable() {
+    let session = setup_db().await;
+    sqlx::query("DROP TABLE allocations")
+        .execute(&session)
+        .await
+        .expect("drop allocations");
+    insert_batch(&session, "batch1").await;
+    let repo = SqlxRepository::new(session);
+
+    let res = repo.get_by_sku("GENERIC-SOFA").await;
+
+    assert!(
+        matches!(res, Err(RepositoryError::Unavailable(_))),
+        "{:?}",
+        res
+    );
+}
+
+#[tokio::test]
+async fn repository_lists_batches_page_by_page() {
+    let session = setup_db().await;
+    for reference in ["batch1", "batch2", "batch3"] {
+        insert_batch(&session, reference).await;
+    }
+    let repo = SqlxRepository::new(session);
+
+    let first = repo
+        .list_page(&BatchFilter::default(), None, 2)
+        .await
+        .expect("list first page");
+    let second = repo
+        .list_page(&BatchFilter::default(), first.next_after, 2)
+        .await
+        .expect("list second page");
+
+    let references = |page: &infrastructure::repositories::BatchPage| {
+        page.batches
+            .iter()
+            .map(|b| b.reference.clone())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(references(&first), vec!["batch1", "batch2"]);
+    assert!(first.next_after.is_some());
+    assert_eq!(references(&second), vec!["batch3"]);
+    assert_eq!(second.next_after, None);
+}
+
+#[tokio::test]
+async fn repository_filters_listed_batches() {
+    let session = setup_db().await;
+    sqlx::query(
+        "INSERT INTO batches (id, reference, sku, _purchased_quantity, eta) VALUES
+            (1, 'in-stock', 'GENERIC-SOFA', 10, null),
+            (2, 'arrived', 'GENERIC-SOFA', 10, '2011-01-01'),
+            (3, 'shipping', 'GENERIC-SOFA', 10, '2011-03-01'),
+            (4, 'other', 'RED-CHAIR', 10, null);
+        INSERT INTO order_lines (id, orderid, sku, qty) VALUES (1, 'order1', 'GENERIC-SOFA', 10);
+        INSERT INTO allocations (orderline_id, batch_id) VALUES (1, 1);",
+    )
+    .execute(&session)
+    .await
+    .expect("insert stock");
+    let repo = SqlxRepository::new(session);
+    let list = |filter: BatchFilter| {
+        let repo = &repo;
+        async move {
+            repo.list_page(&filter, None, 10)
+                .await
+                .expect("list batches")
+                .batches
+                .into_iter()
+                .map(|b| b.reference)
+                .collect::<Vec<_>>()
+        }
+    };
+    let sofa = || BatchFilter {
+        sku: Some("GENERIC-SOFA".to_owned()),
+        ..BatchFilter::default()
+    };
+    let date = |m, d| chrono::NaiveDate::from_ymd_opt(2011, m, d);
+
+    assert_eq!(list(sofa()).await, vec!["in-stock", "arrived", "shipping"]);
+    assert_eq!(
+        list(BatchFilter {
+            eta_from: date(1, 1),
+            eta_to: date(2, 1),
+            ..sofa()
+        })
+        .await,
+        vec!["arrived"]
+    );
+    assert_eq!(
+        list(BatchFilter {
+            in_stock_on: date(2, 1),
+            ..sofa()
+        })
+        .await,
+        vec!["in-stock", "arrived"]
+    );
+    assert_eq!(
+        list(BatchFilter {
+            has_availability: true,
+            ..sofa()
+        })
+        .await,
+        vec!["arrived", "shipping"]
+    );
+    let page = repo
+        .list_page(&sofa(), None, 1)
+        .await
+        .expect("list batches");
+    assert_eq!(page.batches[0].allocated_quantity, 10);
+    assert_eq!(page.batches[0].available_quantity(), 0);
+}
+
+#[tokio::test]
+async fn product_lookups_use_the_sku_index() {
+    let session = setup_db().await;
+
+    let plan: Vec<String> = sqlx::query(&format!("EXPLAIN QUERY PLAN {}", PRODUCT_BATCHES_QUERY))
+        .bind("GENERIC-SOFA")
+        .fetch_all(&session)
+        .await
+        .expect("explain query")
+        .iter()
+        .map(|row| row.get("detail"))
+        .collect();
+
+    assert!(
+        plan.iter().any(|step| step.contains("INDEX batches_sku")),
+        "{:?}",
+        plan
+    );
+}
+
+#[tokio::test]
+async fn repository_rehydrates_each_batch_with_exactly_its_own_lines() {
+    let session = setup_db().await;
+    sqlx::query(
+        "INSERT INTO batches (id, reference, sku, _purchased_quantity, eta) VALUES
+            (1, 'batch1', 'GENERIC-SOFA', 100, null),
+            (2, 'batch2', 'GENERIC-SOFA', 100, null),
+            (3, 'batch3', 'GENERIC-SOFA', 100, null),
+            (4, 'other', 'RED-CHAIR', 100, null);
+        INSERT INTO order_lines (id, orderid, sku, qty) VALUES
+            (1, 'order1', 'GENERIC-SOFA', 1),
+            (2, 'order1', 'GENERIC-SOFA', 2),
+            (3, 'order2', 'GENERIC-SOFA', 3),
+            (4, 'order3', 'GENERIC-SOFA', 4),
+            (5, 'order1', 'RED-CHAIR', 5);
+        INSERT INTO allocations (orderline_id, batch_id) VALUES
+            (1, 1), (2, 2), (3, 2), (5, 4);",
+    )
+    .execute(&session)
+    .await
+    .expect("insert stock");
+    let repo = SqlxRepository::new(session);
+    let line = |orderid: &str, sku: &str, qty| {
+        model::OrderLine::new(orderid.to_owned(), sku.to_owned(), qty)
+    };
+    let expected = |reference: &str| match reference {
+        "batch1" => HashSet::from([line("order1", "GENERIC-SOFA", 1)]),
+        "batch2" => HashSet::from([
+            line("order1", "GENERIC-SOFA", 2),
+            line("order2", "GENERIC-SOFA", 3),
+        ]),
+        "other" => HashSet::from([line("order1", "RED-CHAIR", 5)]),
+        _ => HashSet::new(),
+    };
+
+    for reference in ["batch1", "batch2", "batch3", "other"] {
+        let batch = repo
+            .get(reference)
+            .await
+            .expect("get batch")
+            .expect("batch exists");
+        assert_eq!(
+            batch.allocations(),
+            &expected(reference),
+            "get {}",
+            reference
+        );
+    }
+    let product = repo
+        .get_by_sku("GENERIC-SOFA")
+        .await
+        .expect("get product")
+        .expect("product exists");
+    assert_eq!(product.batches().len(), 3);
+    for batch in product.batches() {
+        assert_eq!(
+            batch.allocations(),
+            &expected(batch.reference()),
+            "get_by_sku {}",
+            batch.reference()
+        );
+    }
+    let batches = repo.list().await.expect("list batches");
+    assert_eq!(batches.len(), 4);
+    for batch in &batches {
+        assert_eq!(
+            batch.allocations(),
+            &expected(batch.reference()),
+            "list {}",
+            batch.reference()
+        );
+    }
+}
+
+async fn count(session: &SqlitePool, table: &str) -> i64 {
+    sqlx::query(&format!("SELECT COUNT(*) AS n FROM {}", table))
+        .fetch_one(session)
+        .await
+        .expect("count rows")
+        .get("n")
+}
+
 async fn insert_order_line(session: &SqlitePool) -> u32 {
     sqlx::query(
         "INSERT INTO order_lines (orderid, sku, qty)
@@ -0,0 +1,153 @@
+use domain::{
+    events::Event,
+    model,
+    repository::{Repository, RepositoryError},
+    unit_of_work::{UnitOfWork, UnitOfWorkFactory},
+};
+use infrastructure::{repositories::SqlxRepository, unit_of_work::SqlxUnitOfWorkFactory};
+use sqlx::sqlite::SqlitePool;
+use tokio::sync::Barrier;
+
+#[tokio::test]
+async fn unit_of_work_can_allocate_and_commit() {
+    let session = setup_db().await;
+    insert_batch(&session, "batch1").await;
+    let factory = SqlxUnitOfWorkFactory::new(session.clone());
+
+    let mut uow = factory.begin().await.expect("begin");
+    let mut product = get_product(uow.products()).await;
+    product
+        .allocate(model::OrderLine::new(
+            "order1".to_owned(),
+            "HIPSTER-WORKBENCH".to_owned(),
+            10,
+        ))
+        .expect("allocate");
+    uow.products().save(&product).await.expect("save product");
+    uow.commit().await.expect("commit");
+
+    let product = get_product(&SqlxRepository::new(session)).await;
+    assert_eq!(product.version_number(), 1);
+    assert_eq!(product.batches()[0].available_quantity(), 90);
+    assert_eq!(
+        uow.collect_new_events(),
+        vec![Event::Allocated {
+            orderid: "order1".to_owned(),
+            sku: "HIPSTER-WORKBENCH".to_owned(),
+            qty: 10,
+            batchref: "batch1".to_owned(),
+        }]
+    );
+}
+
+#[tokio::test]
+async fn unit_of_work_rolls_back_uncommitted_work_when_dropped() {
+    let session = setup_db().await;
+    insert_batch(&session, "batch1").await;
+    let factory = SqlxUnitOfWorkFactory::new(session.clone());
+
+    let mut uow = factory.begin().await.expect("begin");
+    let mut product = get_product(uow.products()).await;
+    product
+        .allocate(model::OrderLine::new(
+            "order1".to_owned(),
+            "HIPSTER-WORKBENCH".to_owned(),
+            10,
+        ))
+        .expect("allocate");
+    uow.products().save(&product).await.expect("save product");
+    assert!(uow.collect_new_events().is_empty());
+    drop(uow);
+
+    let product = get_product(&SqlxRepository::new(session)).await;
+    assert_eq!(product.version_number(), 0);
+    assert_eq!(product.batches()[0].available_quantity(), 100);
+}
+
+#[tokio::test]
+async fn concurrent_units_of_work_wait_for_each_other() {
+    let session = setup_db().await;
+    insert_batch(&session, "batch1").await;
+    let factory = SqlxUnitOfWorkFactory::new(session.clone());
+
+    let allocate = |orderid: &'static str| {
+        let factory = &factory;
+        async move {
+            let mut uow = factory.begin().await?;
+            let mut product = get_product(uow.products()).await;
+            tokio::task::yield_now().await;
+            product
+                .allocate(model::OrderLine::new(
+                    orderid.to_owned(),
+                    "HIPSTER-WORKBENCH".to_owned(),
+                    10,
+                ))
+                .expect("allocate");
+            uow.products().save(&product).await?;
+            uow.commit().await
+        }
+    };
+    let (first, second) = tokio::join!(allocate("order1"), allocate("order2"));
+
+    assert_eq!(first, Ok(()));
+    assert_eq!(second, Ok(()));
+    let product = get_product(&SqlxRepository::new(session)).await;
+    assert_eq!(product.version_number(), 2);
+    assert_eq!(product.batches()[0].available_quantity(), 80);
+}
+
+#[tokio::test]
+async fn lock_conflicts_are_reported_as_retryable() {
+    let session = setup_db().await;
+    insert_batch(&session, "batch1").await;
+    let both_read = Barrier::new(2);
+
+    let read_then_write = || {
+        let (session, both_read) = (&session, &both_read);
+        async move {
+            let mut tx = session.begin().await?;
+            sqlx::query("SELECT * FROM batches")
+                .fetch_all(&mut tx)
+                .await?;
+            both_read.wait().await;
+            sqlx::query("UPDATE batches SET _purchased_quantity = 50")
+                .execute(&mut tx)
+                .await?;
+            tx.commit().await
+        }
+    };
+    let (first, second) = tokio::join!(read_then_write(), read_then_write());
+
+    let err = first.and(second).expect_err("one transaction deadlocks");
+    let err = RepositoryError::from(infrastructure::Error::from(err));
+    assert!(matches!(err, RepositoryError::Busy(_)), "{:?}", err);
+}
+
+async fn get_product(repo: &impl Repository) -> model::Product {
+    repo.get_by_sku("HIPSTER-WORKBENCH")
+        .await
+        .expect("get product")
+        .expect("product exists")
+}
+
+async fn insert_batch(session: &SqlitePool, reference: &str) {
+    sqlx::query(
+        "INSERT INTO batches (reference, sku, _purchased_quantity, eta)
+        VALUES ($1, 'HIPSTER-WORKBENCH', 100, null)",
+    )
+    .bind(reference)
+    .execute(session)
+    .await
+    .expect("insert batch");
+}
+
+async fn setup_db() -> SqlitePool {
+    let db = SqlitePool::connect("sqlite::memory:")
+        .await
+        .expect("Failed to connect to db");
+    sqlx::migrate!("./migrations")
+        .run(&db)
+        .await
+        .expect("running migrations");
+    db
+}
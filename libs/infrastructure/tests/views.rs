@@ -0,0 +1,179 @@
+use chrono::NaiveDate;
+use infrastructure::views;
+use sqlx::sqlite::SqlitePool;
+
+#[tokio::test]
+async fn allocations_view_follows_added_and_removed_allocations() {
+    let session = setup_db().await;
+    insert_stock(&session).await;
+
+    let mut conn = session.acquire().await.expect("acquire connection");
+    views::add_allocation(&mut conn, "order1", "SMALL-TABLE", 2, "batch1")
+        .await
+        .expect("add allocation");
+    views::add_allocation(&mut conn, "order1", "BLUE-LAMP", 3, "batch2")
+        .await
+        .expect("add allocation");
+    views::remove_allocation(&mut conn, "order1", "BLUE-LAMP", 3, "batch2")
+        .await
+        .expect("remove allocation");
+    drop(conn);
+
+    let allocations = views::allocations(&session, "order1")
+        .await
+        .expect("query allocations");
+    assert_eq!(
+        allocations,
+        vec![views::Allocation {
+            sku: "SMALL-TABLE".to_owned(),
+            qty: 2,
+            batchref: "batch1".to_owned(),
+            eta: NaiveDate::from_ymd_opt(2011, 1, 2),
+        }]
+    );
+    assert!(views::allocations(&session, "order3")
+        .await
+        .expect("query allocations")
+        .is_empty());
+}
+
+#[tokio::test]
+async fn allocations_view_keeps_one_row_per_order_sku_and_batch() {
+    let session = setup_db().await;
+    insert_stock(&session).await;
+    let mut conn = session.acquire().await.expect("acquire connection");
+
+    views::add_allocation(&mut conn, "order1", "BLUE-LAMP", 3, "batch2")
+        .await
+        .expect("add allocation");
+    views::add_allocation(&mut conn, "order1", "BLUE-LAMP", 4, "batch2")
+        .await
+        .expect("add allocation");
+    views::remove_allocation(&mut conn, "order1", "BLUE-LAMP", 3, "batch2")
+        .await
+        .expect("remove allocation");
+    views::remove_allocation(&mut conn, "order1", "BLUE-LAMP", 4, "batch1")
+        .await
+        .expect("remove allocation from another batch");
+    drop(conn);
+
+    assert_eq!(
+        views::allocations(&session, "order1")
+            .await
+            .expect("query allocations"),
+        vec![views::Allocation {
+            sku: "BLUE-LAMP".to_owned(),
+            qty: 4,
+            batchref: "batch2".to_owned(),
+            eta: None,
+        }]
+    );
+}
+
+#[tokio::test]
+async fn rebuilding_the_allocations_view_discards_drift() {
+    let session = setup_db().await;
+    insert_stock(&session).await;
+    sqlx::query(
+        "INSERT INTO order_lines (id, orderid, sku, qty) VALUES
+            (1, 'order1', 'SMALL-TABLE', 2),
+            (2, 'order1', 'BLUE-LAMP', 3),
+            (3, 'order2', 'BLUE-LAMP', 4);
+        INSERT INTO allocations (orderline_id, batch_id) VALUES (1, 1), (2, 2), (3, 2);",
+    )
+    .execute(&session)
+    .await
+    .expect("insert allocations");
+    views::add_allocation(
+        &mut session.acquire().await.expect("acquire connection"),
+        "order9",
+        "BLUE-LAMP",
+        1,
+        "batch2",
+    )
+    .await
+    .expect("add stale allocation");
+
+    let rows = views::rebuild_allocations_view(&session)
+        .await
+        .expect("rebuild view");
+
+    assert_eq!(rows, 3);
+    assert!(views::allocations(&session, "order9")
+        .await
+        .expect("query allocations")
+        .is_empty());
+    assert_eq!(
+        views::allocations(&session, "order1")
+            .await
+            .expect("query allocations"),
+        vec![
+            views::Allocation {
+                sku: "BLUE-LAMP".to_owned(),
+                qty: 3,
+                batchref: "batch2".to_owned(),
+                eta: None,
+            },
+            views::Allocation {
+                sku: "SMALL-TABLE".to_owned(),
+                qty: 2,
+                batchref: "batch1".to_owned(),
+                eta: NaiveDate::from_ymd_opt(2011, 1, 2),
+            },
+        ]
+    );
+}
+
+#[tokio::test]
+async fn rebuilding_the_allocations_view_adds_up_lines_of_an_order_in_one_batch() {
+    let session = setup_db().await;
+    insert_stock(&session).await;
+    sqlx::query(
+        "INSERT INTO order_lines (id, orderid, sku, qty) VALUES
+            (1, 'order1', 'BLUE-LAMP', 3),
+            (2, 'order1', 'BLUE-LAMP', 5);
+        INSERT INTO allocations (orderline_id, batch_id) VALUES (1, 2), (2, 2);",
+    )
+    .execute(&session)
+    .await
+    .expect("insert allocations");
+
+    let rows = views::rebuild_allocations_view(&session)
+        .await
+        .expect("rebuild view");
+
+    assert_eq!(rows, 1);
+    assert_eq!(
+        views::allocations(&session, "order1")
+            .await
+            .expect("query allocations"),
+        vec![views::Allocation {
+            sku: "BLUE-LAMP".to_owned(),
+            qty: 8,
+            batchref: "batch2".to_owned(),
+            eta: None,
+        }]
+    );
+}
+
+async fn insert_stock(session: &SqlitePool) {
+    sqlx::query(
+        "INSERT INTO batches (id, reference, sku, _purchased_quantity, eta) VALUES
+            (1, 'batch1', 'SMALL-TABLE', 100, '2011-01-02'),
+            (2, 'batch2', 'BLUE-LAMP', 100, null)",
+    )
+    .execute(session)
+    .await
+    .expect("insert stock");
+}
+
+async fn setup_db() -> SqlitePool {
+    let db = SqlitePool::connect("sqlite::memory:")
+        .await
+        .expect("Failed to connect to db");
+    sqlx::migrate!("./migrations")
+        .run(&db)
+        .await
+        .expect("running migrations");
+    db
+}
@@ -0,0 +1,28 @@
+use domain::repository::RepositoryError;
+
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum Error {
+    #[error("Invalid sku '{0}'")]
+    InvalidSku(String),
+    #[error("Unknown batch '{0}'")]
+    UnknownBatch(String),
+    #[error("Batch '{0}' already exists")]
+    DuplicateBatch(String),
+    #[error("Order '{orderid}' has no allocated line for '{sku}'")]
+    NotAllocated { orderid: String, sku: String },
+    #[error(transparent)]
+    Domain(#[from] domain::Error),
+    #[error(transparent)]
+    Repository(#[from] RepositoryError),
+}
+
+impl Error {
+    /// Whether the operation lost a race with a concurrent one and may be
+    /// retried.
+    pub fn is_concurrency_conflict(&self) -> bool {
+        matches!(
+            self,
+            Self::Repository(RepositoryError::Concurrency { .. } | RepositoryError::Busy(_))
+        )
+    }
+}
@@ -0,0 +1,128 @@
+//! One handler per command; each works through the unit of work it is given
+//! and commits it once the change is complete.
+use std::sync::Arc;
+
+use crate::Error;
+use domain::{
+    allocation::AllocationStrategies,
+    clock::Clock,
+    model,
+    repository::{Repository, RepositoryError},
+    unit_of_work::UnitOfWork,
+};
+
+pub async fn allocate<U: UnitOfWork>(
+    orderid: String,
+    sku: String,
+    qty: u32,
+    strategies: &AllocationStrategies,
+    clock: &Arc<dyn Clock>,
+    uow: &mut U,
+) -> Result<String, Error> {
+    let mut product = uow
+        .products()
+        .get_by_sku(&sku)
+        .await?
+        .ok_or_else(|| Error::InvalidSku(sku.clone()))?;
+    product.set_strategy(strategies.for_sku(&sku));
+    product.set_clock(clock.clone());
+    let line = model::OrderLine::new(orderid, sku, qty);
+    let result = product.allocate(line);
+    // Saved even when out of stock, so that the `OutOfStock` event is
+    // committed along with the product.
+    uow.products().save(&product).await?;
+    uow.commit().await?;
+    Ok(result?)
+}
+
+pub async fn allocate_split<U: UnitOfWork>(
+    orderid: String,
+    sku: String,
+    qty: u32,
+    strategies: &AllocationStrategies,
+    clock: &Arc<dyn Clock>,
+    uow: &mut U,
+) -> Result<Vec<(String, u32)>, Error> {
+    let mut product = uow
+        .products()
+        .get_by_sku(&sku)
+        .await?
+        .ok_or_else(|| Error::InvalidSku(sku.clone()))?;
+    product.set_strategy(strategies.for_sku(&sku));
+    product.set_clock(clock.clone());
+    let line = model::OrderLine::new(orderid, sku, qty);
+    let result = product.allocate_split(line);
+    uow.products().save(&product).await?;
+    uow.commit().await?;
+    Ok(result?)
+}
+
+pub async fn add_batch<U: UnitOfWork>(
+    reference: String,
+    sku: String,
+    qty: u32,
+    eta: Option<chrono::NaiveDate>,
+    expiry: Option<chrono::NaiveDate>,
+    uow: &mut U,
+) -> Result<(), Error> {
+    if uow.products().get(&reference).await?.is_some() {
+        return Err(Error::DuplicateBatch(reference));
+    }
+    let batch = model::Batch::new(reference.clone(), sku, qty, eta).with_expiry(expiry);
+    match uow.products().add(batch).await {
+        // Lost a race with a concurrent creation of the same batch.
+        Err(RepositoryError::Duplicate(_)) => return Err(Error::DuplicateBatch(reference)),
+        res => res?,
+    }
+    uow.commit().await?;
+    Ok(())
+}
+
+pub async fn change_batch_quantity<U: UnitOfWork>(
+    reference: String,
+    qty: u32,
+    strategies: &AllocationStrategies,
+    clock: &Arc<dyn Clock>,
+    uow: &mut U,
+) -> Result<(), Error> {
+    let batch = uow
+        .products()
+        .get(&reference)
+        .await?
+        .ok_or_else(|| Error::UnknownBatch(reference.clone()))?;
+    let mut product = uow
+        .products()
+        .get_by_sku(batch.sku())
+        .await?
+        .ok_or_else(|| Error::UnknownBatch(reference.clone()))?;
+    // Overflowing lines are reallocated like any other line of the SKU.
+    product.set_strategy(strategies.for_sku(batch.sku()));
+    product.set_clock(clock.clone());
+    product.change_batch_quantity(&reference, qty);
+    uow.products().save(&product).await?;
+    uow.commit().await?;
+    Ok(())
+}
+
+pub async fn deallocate<U: UnitOfWork>(
+    orderid: String,
+    sku: String,
+    qty: u32,
+    uow: &mut U,
+) -> Result<String, Error> {
+    let not_allocated = || Error::NotAllocated {
+        orderid: orderid.clone(),
+        sku: sku.clone(),
+    };
+    // A line of an unknown product cannot be allocated either.
+    let mut product = uow
+        .products()
+        .get_by_sku(&sku)
+        .await?
+        .ok_or_else(not_allocated)?;
+    let line = model::OrderLine::new(orderid.clone(), sku.clone(), qty);
+    let batchref = product.deallocate(line).ok_or_else(not_allocated)?;
+    uow.products().save(&product).await?;
+    uow.commit().await?;
+    Ok(batchref)
+}
@@ -0,0 +1,6 @@
+mod error;
+pub mod handlers;
+pub mod messagebus;
+
+pub use error::Error;
+pub use messagebus::{EventHandler, MessageBus, Outcome};
@@ -0,0 +1,185 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
+
+use async_trait::async_trait;
+use domain::{
+    allocation::AllocationStrategies,
+    clock::{Clock, SystemClock},
+    commands::Command,
+    events::Event,
+    unit_of_work::{UnitOfWork, UnitOfWorkFactory},
+};
+
+use crate::{handlers, Error};
+
+/// How many times a message is handled when the product it touches keeps
+/// being modified concurrently.
+pub const MAX_ATTEMPTS: usize = 5;
+
+/// Reacts to an event inside its own unit of work; the events of whatever
+/// it commits are processed next.
+#[async_trait]
+pub trait EventHandler<U>: Send + Sync {
+    async fn handle(&self, event: &Event, uow: &mut U) -> Result<(), Error>;
+}
+
+/// What handling a command produced for its caller.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Done,
+    /// The batch an `Allocate` or `Deallocate` touched.
+    Batchref(String),
+    /// The `(batchref, qty)` fragments an `AllocateSplit` was split into.
+    Fragments(Vec<(String, u32)>),
+}
+
+impl Outcome {
+    pub fn batchref(self) -> Option<String> {
+        match self {
+            Self::Batchref(batchref) => Some(batchref),
+            _ => None,
+        }
+    }
+
+    pub fn fragments(self) -> Option<Vec<(String, u32)>> {
+        match self {
+            Self::Fragments(fragments) => Some(fragments),
+            _ => None,
+        }
+    }
+}
+
+type EventHandlers<U> = HashMap<&'static str, Vec<Box<dyn EventHandler<U>>>>;
+
+/// Dispatches commands to their handler, then every event raised along the
+/// way to the handlers registered for it, until no events are left.
+///
+/// Every command and every event handler call runs in a fresh unit of work.
+pub struct MessageBus<F: UnitOfWorkFactory> {
+    uow_factory: F,
+    event_handlers: EventHandlers<F::UnitOfWork>,
+    strategies: AllocationStrategies,
+    clock: Arc<dyn Clock>,
+}
+
+impl<F: UnitOfWorkFactory> MessageBus<F> {
+    pub fn new(uow_factory: F) -> Self {
+        Self {
+            uow_factory,
+            event_handlers: HashMap::new(),
+            strategies: AllocationStrategies::default(),
+            clock: Arc::new(SystemClock::default()),
+        }
+    }
+
+    /// Makes allocation decisions as of the date of `clock`.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Allocates each SKU with its strategy in `strategies`.
+    pub fn with_strategies(mut self, strategies: AllocationStrategies) -> Self {
+        self.strategies = strategies;
+        self
+    }
+
+    /// Registers `handler` for events named `event` (see [`Event::name`]).
+    pub fn with_event_handler<H>(mut self, event: &'static str, handler: H) -> Self
+    where
+        H: EventHandler<F::UnitOfWork> + 'static,
+    {
+        self.event_handlers
+            .entry(event)
+            .or_default()
+            .push(Box::new(handler));
+        self
+    }
+
+    /// Handles `command` and every event it causes.
+    ///
+    /// Returns the batch reference for `Allocate` and `Deallocate`, and the
+    /// fragments for `AllocateSplit`. Errors of
+    /// the command handler are returned after the raised events have been
+    /// processed; errors of event handlers are logged and swallowed. Only
+    /// events of committed work are processed.
+    pub async fn handle(&self, command: Command) -> Result<Outcome, Error> {
+        let mut attempt = 1;
+        let (result, raised) = loop {
+            let mut uow = self.uow_factory.begin().await?;
+            match self.handle_command(command.clone(), &mut uow).await {
+                Err(err) if err.is_concurrency_conflict() && attempt < MAX_ATTEMPTS => attempt += 1,
+                result => break (result, uow.collect_new_events()),
+            }
+        };
+
+        let mut queue = VecDeque::from(raised);
+        while let Some(event) = queue.pop_front() {
+            for handler in self.event_handlers.get(event.name()).into_iter().flatten() {
+                let mut attempt = 1;
+                loop {
+                    let mut uow = match self.uow_factory.begin().await {
+                        Ok(uow) => uow,
+                        Err(err) => {
+                            log::error!("handling {:?}: {}", event, err);
+                            break;
+                        }
+                    };
+                    let result = handler.handle(&event, &mut uow).await;
+                    queue.extend(uow.collect_new_events());
+                    match result {
+                        Ok(()) => break,
+                        Err(err) if err.is_concurrency_conflict() && attempt < MAX_ATTEMPTS => {
+                            attempt += 1
+                        }
+                        Err(err) => {
+                            log::error!("handling {:?}: {}", event, err);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    async fn handle_command(
+        &self,
+        command: Command,
+        uow: &mut F::UnitOfWork,
+    ) -> Result<Outcome, Error> {
+        match command {
+            Command::Allocate { orderid, sku, qty } => {
+                handlers::allocate(orderid, sku, qty, &self.strategies, &self.clock, uow)
+                    .await
+                    .map(Outcome::Batchref)
+            }
+            Command::AllocateSplit { orderid, sku, qty } => {
+                handlers::allocate_split(orderid, sku, qty, &self.strategies, &self.clock, uow)
+                    .await
+                    .map(Outcome::Fragments)
+            }
+            Command::CreateBatch {
+                reference,
+                sku,
+                qty,
+                eta,
+                expiry,
+            } => handlers::add_batch(reference, sku, qty, eta, expiry, uow)
+                .await
+                .map(|()| Outcome::Done),
+            Command::ChangeBatchQuantity { reference, qty } => {
+                handlers::change_batch_quantity(reference, qty, &self.strategies, &self.clock, uow)
+                    .await
+                    .map(|()| Outcome::Done)
+            }
+            Command::Deallocate { orderid, sku, qty } => {
+                handlers::deallocate(orderid, sku, qty, uow)
+                    .await
+                    .map(Outcome::Batchref)
+            }
+        }
+    }
+}
@@ -0,0 +1,532 @@
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use domain::{
+    allocation::{AllocationStrategies, LargestRemaining},
+    clock::ManualClock,
+    commands::Command,
+    events::Event,
+    fakes::{FakeRepository, FakeUnitOfWork, FakeUnitOfWorkFactory},
+    model,
+    repository::{Repository, RepositoryError},
+    unit_of_work::UnitOfWork,
+};
+use service_layer::{messagebus::MAX_ATTEMPTS, Error, EventHandler, MessageBus, Outcome};
+
+#[derive(Clone, Default)]
+struct RecordingHandler {
+    handled: Arc<Mutex<Vec<Event>>>,
+}
+
+impl RecordingHandler {
+    fn handled(&self) -> Vec<Event> {
+        self.handled.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl<U: Send> EventHandler<U> for RecordingHandler {
+    async fn handle(&self, event: &Event, _uow: &mut U) -> Result<(), Error> {
+        self.handled.lock().unwrap().push(event.clone());
+        Ok(())
+    }
+}
+
+/// Takes back every line it sees allocated, raising `Deallocated`. Unless
+/// `commit` is set, it fails after saving instead of committing.
+struct CancellingHandler {
+    commit: bool,
+}
+
+#[async_trait]
+impl EventHandler<FakeUnitOfWork> for CancellingHandler {
+    async fn handle(&self, event: &Event, uow: &mut FakeUnitOfWork) -> Result<(), Error> {
+        if let Event::Allocated {
+            orderid, sku, qty, ..
+        } = event
+        {
+            let mut product = uow.products().get_by_sku(sku).await?.unwrap();
+            product.deallocate(model::OrderLine::new(orderid.clone(), sku.clone(), *qty));
+            uow.products().save(&product).await?;
+            if !self.commit {
+                return Err(Error::InvalidSku(sku.clone()));
+            }
+            uow.commit().await?;
+        }
+        Ok(())
+    }
+}
+
+fn today() -> chrono::NaiveDate {
+    chrono::NaiveDate::from_ymd_opt(2011, 1, 1).unwrap()
+}
+
+/// A bus whose clock is frozen at `today()`.
+fn bus(repo: Arc<FakeRepository>) -> MessageBus<FakeUnitOfWorkFactory> {
+    MessageBus::new(FakeUnitOfWorkFactory::new(repo))
+        .with_clock(Arc::new(ManualClock::new(today())))
+}
+
+fn create_batch(reference: &str, sku: &str, qty: u32) -> Command {
+    Command::CreateBatch {
+        reference: reference.to_owned(),
+        sku: sku.to_owned(),
+        qty,
+        eta: None,
+        expiry: None,
+    }
+}
+
+fn conflict(sku: &str) -> RepositoryError {
+    RepositoryError::Concurrency {
+        sku: sku.to_owned(),
+        version_number: 1,
+    }
+}
+
+fn allocate(orderid: &str, sku: &str, qty: u32) -> Command {
+    Command::Allocate {
+        orderid: orderid.to_owned(),
+        sku: sku.to_owned(),
+        qty,
+    }
+}
+
+#[tokio::test]
+async fn allocate_returns_the_batch_reference() {
+    let bus = bus(Arc::new(FakeRepository::new()));
+    bus.handle(create_batch("batch1", "COMPLICATED-LAMP", 100))
+        .await
+        .expect("create batch");
+
+    let res = bus.handle(allocate("o1", "COMPLICATED-LAMP", 10)).await;
+
+    assert_eq!(res, Ok(Outcome::Batchref("batch1".to_owned())));
+}
+
+#[tokio::test]
+async fn allocate_retries_when_the_product_was_modified_concurrently() {
+    let repo = Arc::new(FakeRepository::new());
+    let bus = bus(repo.clone());
+    bus.handle(create_batch("batch1", "COMPLICATED-LAMP", 100))
+        .await
+        .expect("create batch");
+    repo.fail_next_saves(MAX_ATTEMPTS - 1, conflict("COMPLICATED-LAMP"));
+
+    let res = bus.handle(allocate("o1", "COMPLICATED-LAMP", 10)).await;
+
+    assert_eq!(res, Ok(Outcome::Batchref("batch1".to_owned())));
+}
+
+#[tokio::test]
+async fn allocate_gives_up_after_max_attempts() {
+    let repo = Arc::new(FakeRepository::new());
+    let recorder = RecordingHandler::default();
+    let bus = bus(repo.clone()).with_event_handler("Allocated", recorder.clone());
+    bus.handle(create_batch("batch1", "COMPLICATED-LAMP", 100))
+        .await
+        .expect("create batch");
+    repo.fail_next_saves(MAX_ATTEMPTS, conflict("COMPLICATED-LAMP"));
+
+    let res = bus.handle(allocate("o1", "COMPLICATED-LAMP", 10)).await;
+
+    assert_eq!(res, Err(Error::Repository(conflict("COMPLICATED-LAMP"))));
+    assert!(recorder.handled().is_empty());
+}
+
+#[tokio::test]
+async fn unavailable_repository_is_not_retried() {
+    let repo = Arc::new(FakeRepository::new());
+    let bus = bus(repo.clone());
+    bus.handle(create_batch("batch1", "COMPLICATED-LAMP", 100))
+        .await
+        .expect("create batch");
+    let unavailable = RepositoryError::Unavailable("disk I/O error".to_owned());
+    repo.fail_next_saves(1, unavailable.clone());
+
+    let res = bus.handle(allocate("o1", "COMPLICATED-LAMP", 10)).await;
+
+    assert_eq!(res, Err(Error::Repository(unavailable)));
+    assert_eq!(
+        bus.handle(allocate("o1", "COMPLICATED-LAMP", 10)).await,
+        Ok(Outcome::Batchref("batch1".to_owned()))
+    );
+}
+
+#[tokio::test]
+async fn allocate_split_returns_the_fragments_and_raises_an_event_for_each() {
+    let recorder = RecordingHandler::default();
+    let bus =
+        bus(Arc::new(FakeRepository::new())).with_event_handler("Allocated", recorder.clone());
+    bus.handle(create_batch("batch1", "COMPLICATED-LAMP", 10))
+        .await
+        .expect("create batch");
+    bus.handle(create_batch("batch2", "COMPLICATED-LAMP", 10))
+        .await
+        .expect("create batch");
+
+    let res = bus
+        .handle(Command::AllocateSplit {
+            orderid: "o1".to_owned(),
+            sku: "COMPLICATED-LAMP".to_owned(),
+            qty: 15,
+        })
+        .await;
+
+    let fragments = match res {
+        Ok(Outcome::Fragments(fragments)) => fragments,
+        other => panic!("expected fragments, got {:?}", other),
+    };
+    assert_eq!(fragments.iter().map(|(_, qty)| qty).sum::<u32>(), 15);
+    assert_eq!(fragments.len(), 2);
+    assert_eq!(recorder.handled().len(), 2);
+}
+
+#[tokio::test]
+async fn allocate_uses_the_strategy_configured_for_the_sku() {
+    let strategies =
+        AllocationStrategies::default().with_sku("COMPLICATED-LAMP", Arc::new(LargestRemaining));
+    let bus = bus(Arc::new(FakeRepository::new())).with_strategies(strategies);
+    bus.handle(create_batch("small", "COMPLICATED-LAMP", 10))
+        .await
+        .expect("create batch");
+    bus.handle(Command::CreateBatch {
+        reference: "large".to_owned(),
+        sku: "COMPLICATED-LAMP".to_owned(),
+        qty: 100,
+        eta: Some(today()),
+        expiry: None,
+    })
+    .await
+    .expect("create batch");
+
+    assert_eq!(
+        bus.handle(allocate("o1", "COMPLICATED-LAMP", 5)).await,
+        Ok(Outcome::Batchref("large".to_owned()))
+    );
+}
+
+#[tokio::test]
+async fn allocate_treats_shipments_whose_eta_has_passed_as_in_stock() {
+    for (today, expected) in [
+        (today(), "warehouse"),
+        (today() + chrono::Duration::days(4), "shipment"),
+    ] {
+        let bus =
+            bus(Arc::new(FakeRepository::new())).with_clock(Arc::new(ManualClock::new(today)));
+        bus.handle(Command::CreateBatch {
+            reference: "shipment".to_owned(),
+            sku: "COMPLICATED-LAMP".to_owned(),
+            qty: 100,
+            eta: Some(self::today() + chrono::Duration::days(1)),
+            expiry: None,
+        })
+        .await
+        .expect("create batch");
+        bus.handle(create_batch("warehouse", "COMPLICATED-LAMP", 100))
+            .await
+            .expect("create batch");
+
+        // Once both are in stock, neither is preferred to the other.
+        assert_eq!(
+            bus.handle(allocate("o1", "COMPLICATED-LAMP", 10)).await,
+            Ok(Outcome::Batchref(expected.to_owned())),
+            "on {}",
+            today
+        );
+    }
+}
+
+#[tokio::test]
+async fn allocate_errors_for_invalid_sku() {
+    let bus = bus(Arc::new(FakeRepository::new()));
+    bus.handle(create_batch("b1", "AREALSKU", 100))
+        .await
+        .expect("create batch");
+
+    let res = bus.handle(allocate("o1", "NONEXISTENTSKU", 10)).await;
+
+    assert_eq!(res, Err(Error::InvalidSku("NONEXISTENTSKU".to_owned())));
+}
+
+#[tokio::test]
+async fn raised_events_are_dispatched_to_registered_handlers() {
+    let recorder = RecordingHandler::default();
+    let bus = bus(Arc::new(FakeRepository::new()))
+        .with_event_handler("Allocated", recorder.clone())
+        .with_event_handler("OutOfStock", recorder.clone());
+    bus.handle(create_batch("batch1", "POPULAR-CURTAINS", 10))
+        .await
+        .expect("create batch");
+
+    bus.handle(allocate("o1", "POPULAR-CURTAINS", 10))
+        .await
+        .expect("allocate");
+    let res = bus.handle(allocate("o2", "POPULAR-CURTAINS", 1)).await;
+
+    assert_eq!(
+        res,
+        Err(Error::Domain(domain::Error::OutOfStock(
+            "POPULAR-CURTAINS".to_owned()
+        )))
+    );
+    assert_eq!(
+        recorder.handled(),
+        vec![
+            Event::Allocated {
+                orderid: "o1".to_owned(),
+                sku: "POPULAR-CURTAINS".to_owned(),
+                qty: 10,
+                batchref: "batch1".to_owned(),
+            },
+            Event::OutOfStock {
+                sku: "POPULAR-CURTAINS".to_owned()
+            },
+        ]
+    );
+}
+
+#[tokio::test]
+async fn events_raised_by_event_handlers_are_processed_until_the_queue_drains() {
+    let recorder = RecordingHandler::default();
+    let repo = Arc::new(FakeRepository::new());
+    let bus = bus(repo.clone())
+        .with_event_handler("Allocated", CancellingHandler { commit: true })
+        .with_event_handler("Deallocated", recorder.clone());
+    bus.handle(create_batch("batch1", "BLUE-VASE", 10))
+        .await
+        .expect("create batch");
+
+    bus.handle(allocate("o1", "BLUE-VASE", 1))
+        .await
+        .expect("allocate");
+
+    assert_eq!(
+        recorder.handled(),
+        vec![Event::Deallocated {
+            orderid: "o1".to_owned(),
+            sku: "BLUE-VASE".to_owned(),
+            qty: 1,
+            batchref: "batch1".to_owned(),
+        }]
+    );
+    let product = repo.get_by_sku("BLUE-VASE").await.unwrap().unwrap();
+    assert_eq!(product.batches()[0].available_quantity(), 10);
+}
+
+#[tokio::test]
+async fn uncommitted_work_of_a_failing_event_handler_is_rolled_back() {
+    let recorder = RecordingHandler::default();
+    let repo = Arc::new(FakeRepository::new());
+    let bus = bus(repo.clone())
+        .with_event_handler("Allocated", CancellingHandler { commit: false })
+        .with_event_handler("Deallocated", recorder.clone());
+    bus.handle(create_batch("batch1", "BLUE-VASE", 10))
+        .await
+        .expect("create batch");
+
+    bus.handle(allocate("o1", "BLUE-VASE", 1))
+        .await
+        .expect("allocate");
+
+    assert!(recorder.handled().is_empty());
+    let product = repo.get_by_sku("BLUE-VASE").await.unwrap().unwrap();
+    assert_eq!(product.batches()[0].available_quantity(), 9);
+}
+
+#[tokio::test]
+async fn deallocate_frees_the_line_and_raises_deallocated() {
+    let recorder = RecordingHandler::default();
+    let bus =
+        bus(Arc::new(FakeRepository::new())).with_event_handler("Deallocated", recorder.clone());
+    bus.handle(create_batch("batch1", "RUSTY-BENCH", 10))
+        .await
+        .expect("create batch");
+    bus.handle(allocate("o1", "RUSTY-BENCH", 10))
+        .await
+        .expect("allocate");
+
+    let res = bus
+        .handle(Command::Deallocate {
+            orderid: "o1".to_owned(),
+            sku: "RUSTY-BENCH".to_owned(),
+            qty: 10,
+        })
+        .await;
+
+    assert_eq!(res, Ok(Outcome::Batchref("batch1".to_owned())));
+    assert_eq!(
+        bus.handle(allocate("o2", "RUSTY-BENCH", 10)).await,
+        Ok(Outcome::Batchref("batch1".to_owned()))
+    );
+    assert_eq!(
+        recorder.handled(),
+        vec![Event::Deallocated {
+            orderid: "o1".to_owned(),
+            sku: "RUSTY-BENCH".to_owned(),
+            qty: 10,
+            batchref: "batch1".to_owned(),
+        }]
+    );
+}
+
+#[tokio::test]
+async fn deallocate_errors_for_unallocated_line() {
+    let bus = bus(Arc::new(FakeRepository::new()));
+    bus.handle(create_batch("batch1", "RUSTY-BENCH", 10))
+        .await
+        .expect("create batch");
+
+    let res = bus
+        .handle(Command::Deallocate {
+            orderid: "o1".to_owned(),
+            sku: "RUSTY-BENCH".to_owned(),
+            qty: 10,
+        })
+        .await;
+
+    assert_eq!(
+        res,
+        Err(Error::NotAllocated {
+            orderid: "o1".to_owned(),
+            sku: "RUSTY-BENCH".to_owned(),
+        })
+    );
+}
+
+#[tokio::test]
+async fn deallocate_errors_for_unknown_sku() {
+    let bus = bus(Arc::new(FakeRepository::new()));
+
+    let res = bus
+        .handle(Command::Deallocate {
+            orderid: "o1".to_owned(),
+            sku: "MISSING-BENCH".to_owned(),
+            qty: 10,
+        })
+        .await;
+
+    assert_eq!(
+        res,
+        Err(Error::NotAllocated {
+            orderid: "o1".to_owned(),
+            sku: "MISSING-BENCH".to_owned(),
+        })
+    );
+}
+
+#[tokio::test]
+async fn change_batch_quantity_raises_batch_quantity_changed() {
+    let recorder = RecordingHandler::default();
+    let bus = bus(Arc::new(FakeRepository::new()))
+        .with_event_handler("BatchQuantityChanged", recorder.clone());
+    bus.handle(create_batch("batch1", "ADORABLE-SETTEE", 100))
+        .await
+        .expect("create batch");
+
+    bus.handle(Command::ChangeBatchQuantity {
+        reference: "batch1".to_owned(),
+        qty: 50,
+    })
+    .await
+    .expect("change batch quantity");
+
+    assert_eq!(
+        recorder.handled(),
+        vec![Event::BatchQuantityChanged {
+            batchref: "batch1".to_owned(),
+            qty: 50,
+        }]
+    );
+    assert_eq!(
+        bus.handle(allocate("o1", "ADORABLE-SETTEE", 51)).await,
+        Err(Error::Domain(domain::Error::OutOfStock(
+            "ADORABLE-SETTEE".to_owned()
+        )))
+    );
+}
+
+#[tokio::test]
+async fn change_batch_quantity_reallocates_overflowing_lines() {
+    let recorder = RecordingHandler::default();
+    let bus = bus(Arc::new(FakeRepository::new()))
+        .with_event_handler("Deallocated", recorder.clone())
+        .with_event_handler("Allocated", recorder.clone());
+    bus.handle(create_batch("batch1", "INDIFFERENT-TABLE", 50))
+        .await
+        .expect("create batch");
+    bus.handle(Command::CreateBatch {
+        reference: "batch2".to_owned(),
+        sku: "INDIFFERENT-TABLE".to_owned(),
+        qty: 50,
+        eta: Some(today() + chrono::Duration::days(1)),
+        expiry: None,
+    })
+    .await
+    .expect("create batch");
+    bus.handle(allocate("order1", "INDIFFERENT-TABLE", 20))
+        .await
+        .expect("allocate");
+    bus.handle(allocate("order2", "INDIFFERENT-TABLE", 20))
+        .await
+        .expect("allocate");
+
+    bus.handle(Command::ChangeBatchQuantity {
+        reference: "batch1".to_owned(),
+        qty: 25,
+    })
+    .await
+    .expect("change batch quantity");
+
+    assert_eq!(
+        &recorder.handled()[2..],
+        &[
+            Event::Deallocated {
+                orderid: "order2".to_owned(),
+                sku: "INDIFFERENT-TABLE".to_owned(),
+                qty: 20,
+                batchref: "batch1".to_owned(),
+            },
+            Event::Allocated {
+                orderid: "order2".to_owned(),
+                sku: "INDIFFERENT-TABLE".to_owned(),
+                qty: 20,
+                batchref: "batch2".to_owned(),
+            },
+        ]
+    );
+    // batch1 has 5 left, batch2 has 30, so only batch2 fits another 30.
+    assert_eq!(
+        bus.handle(allocate("order3", "INDIFFERENT-TABLE", 30))
+            .await,
+        Ok(Outcome::Batchref("batch2".to_owned()))
+    );
+}
+
+#[tokio::test]
+async fn create_batch_errors_for_duplicate_reference() {
+    let bus = bus(Arc::new(FakeRepository::new()));
+    bus.handle(create_batch("batch1", "CRUNCHY-ARMCHAIR", 100))
+        .await
+        .expect("create batch");
+
+    let res = bus
+        .handle(create_batch("batch1", "CRUNCHY-ARMCHAIR", 10))
+        .await;
+
+    assert_eq!(res, Err(Error::DuplicateBatch("batch1".to_owned())));
+}
+
+#[tokio::test]
+async fn change_batch_quantity_errors_for_unknown_batch() {
+    let bus = bus(Arc::new(FakeRepository::new()));
+
+    let res = bus
+        .handle(Command::ChangeBatchQuantity {
+            reference: "batch1".to_owned(),
+            qty: 50,
+        })
+        .await;
+
+    assert_eq!(res, Err(Error::UnknownBatch("batch1".to_owned())));
+}